@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use lsp_types::notification::Notification;
+use lsp_types::request::{RegisterCapability, Request, UnregisterCapability};
+use lsp_types::{ServerCapabilities, TextDocumentSyncCapability, Uri};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::capabilities::Capabilities;
+use crate::documents::Documents;
+use crate::handlers;
+use crate::jsonrpc::Id;
+use crate::line_index::PositionEncoding;
+use crate::protocol::{Event, Protocol, ProtocolError};
+
+// shared with the reader task
+#[derive(Default)]
+struct State {
+    protocol: Protocol,
+    pending: HashMap<Id, oneshot::Sender<Result<Value>>>,
+    // set once the reader is done, nothing is answered after that
+    closed: bool,
+    position_encoding: PositionEncoding,
+    documents: Documents,
+    capabilities: Capabilities,
+}
+
+// must be created inside a tokio runtime
+#[derive(Clone)]
+pub struct AsyncClient {
+    inner: Arc<Inner>,
+}
+
+type Writer = Arc<tokio::sync::Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+struct Inner {
+    state: Arc<Mutex<State>>,
+    writer: Writer,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl AsyncClient {
+    pub fn new(
+        input: impl AsyncRead + Send + Unpin + 'static,
+        output: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let writer: Writer = Arc::new(tokio::sync::Mutex::new(Box::new(output)));
+
+        let reader = tokio::spawn(read_loop(Box::new(input), state.clone(), writer.clone()));
+
+        Self {
+            inner: Arc::new(Inner {
+                state,
+                writer,
+                reader,
+            }),
+        }
+    }
+
+    pub async fn notify<N: Notification>(&self, params: Option<N::Params>) -> Result<()> {
+        let frames = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.protocol.notify::<N>(params)?;
+
+            drain_frames(&mut state.protocol)
+        };
+
+        self.write(frames).await
+    }
+
+    pub async fn request<R: Request>(&self, params: Option<R::Params>) -> Result<R::Result> {
+        let (tx, rx) = oneshot::channel();
+
+        let (id, frames) = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.closed {
+                anyhow::bail!("server closed the connection");
+            }
+
+            let id = state.protocol.request::<R>(params)?;
+
+            // register before sending, so the response can't arrive first
+            state.pending.insert(id.clone(), tx);
+
+            (id, drain_frames(&mut state.protocol))
+        };
+
+        // also if the caller stops waiting
+        let _waiting = Waiting {
+            state: &self.inner.state,
+            id,
+        };
+
+        self.write(frames).await?;
+
+        let result = rx.await.context("server closed the connection")??;
+
+        serde_json::from_value(result).context("deserializing response result")
+    }
+
+    // UTF-16 until initialized
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.inner.state.lock().unwrap().position_encoding
+    }
+
+    pub fn set_position_encoding(&self, encoding: PositionEncoding) {
+        self.inner.state.lock().unwrap().position_encoding = encoding;
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.inner.state.lock().unwrap().capabilities.clone()
+    }
+
+    pub(crate) fn set_server_capabilities(&self, capabilities: ServerCapabilities) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .capabilities
+            .set_server(capabilities);
+    }
+
+    pub fn is_open(&self, uri: &Uri) -> bool {
+        self.inner.state.lock().unwrap().documents.is_open(uri)
+    }
+
+    pub fn document_version(&self, uri: &Uri) -> Option<i32> {
+        self.inner.state.lock().unwrap().documents.version(uri)
+    }
+
+    pub fn document_text(&self, uri: &Uri) -> Option<String> {
+        let state = self.inner.state.lock().unwrap();
+        state.documents.text(uri).map(str::to_string)
+    }
+
+    pub(crate) fn set_document_sync(&self, capability: Option<&TextDocumentSyncCapability>) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .documents
+            .set_sync(capability);
+    }
+
+    // the writer is locked first, so changes are written in the order they
+    // were made
+    pub(crate) async fn sync_documents<N: Notification>(
+        &self,
+        f: impl FnOnce(&mut Documents, PositionEncoding) -> Result<Option<N::Params>>,
+    ) -> Result<()> {
+        let mut writer = self.inner.writer.lock().await;
+
+        let frames = {
+            let mut state = self.inner.state.lock().unwrap();
+            let encoding = state.position_encoding;
+
+            let Some(params) = f(&mut state.documents, encoding)? else {
+                return Ok(());
+            };
+            state.protocol.notify::<N>(Some(params))?;
+
+            drain_frames(&mut state.protocol)
+        };
+
+        write_frames(&mut writer, frames).await
+    }
+
+    async fn write(&self, frames: Vec<Vec<u8>>) -> Result<()> {
+        write(&self.inner.writer, frames).await
+    }
+}
+
+// removes the pending request when dropped, answered or not
+struct Waiting<'a> {
+    state: &'a Mutex<State>,
+    id: Id,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().pending.remove(&self.id);
+    }
+}
+
+async fn write(writer: &Writer, frames: Vec<Vec<u8>>) -> Result<()> {
+    write_frames(&mut *writer.lock().await, frames).await
+}
+
+async fn write_frames(
+    writer: &mut Box<dyn AsyncWrite + Send + Unpin>,
+    frames: Vec<Vec<u8>>,
+) -> Result<()> {
+    for frame in frames {
+        writer
+            .write_all(&frame)
+            .await
+            .context("writing msg to output")?;
+    }
+
+    writer.flush().await.context("flushing output")
+}
+
+fn drain_frames(protocol: &mut Protocol) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| protocol.poll_frame()).collect()
+}
+
+async fn read_loop(
+    mut input: Box<dyn AsyncRead + Send + Unpin>,
+    state: Arc<Mutex<State>>,
+    writer: Writer,
+) {
+    let mut buf = vec![0; 8 * 1024];
+
+    loop {
+        let read = match input.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+
+        let responses = {
+            let mut state = state.lock().unwrap();
+            state.protocol.receive_bytes(&buf[..read]);
+
+            while let Some(event) = state.protocol.poll_event() {
+                let (id, result) = match event {
+                    Event::Response { id, result, .. } => (
+                        id,
+                        result.map_err(|err| {
+                            anyhow::Error::new(err).context("getting response result")
+                        }),
+                    ),
+                    Event::Error(ProtocolError::MalformedResponse(id, err)) => {
+                        let err = ProtocolError::MalformedResponse(id.clone(), err);
+                        (id, Err(err.into()))
+                    }
+                    // servers wait for an answer, so they get the defaults
+                    Event::Request(request) => {
+                        let registration = matches!(
+                            request.method.as_str(),
+                            RegisterCapability::METHOD | UnregisterCapability::METHOD
+                        )
+                        .then(|| request.params.clone());
+                        let result = handlers::handle(None, &request.method, request.params);
+
+                        let result = match (result, registration) {
+                            (Ok(value), Some(params)) => state
+                                .capabilities
+                                .update(&request.method, params)
+                                .map(|()| value),
+                            (result, _) => result,
+                        };
+                        let _ = state.protocol.respond(request.id, result);
+                        continue;
+                    }
+                    // there are no subscribers to deliver them to
+                    _ => continue,
+                };
+
+                if let Some(tx) = state.pending.remove(&id) {
+                    let _ = tx.send(result);
+                }
+            }
+
+            drain_frames(&mut state.protocol)
+        };
+
+        if !responses.is_empty() && write(&writer, responses).await.is_err() {
+            break;
+        }
+    }
+
+    // wake up everyone still waiting, the server is gone
+    let mut state = state.lock().unwrap();
+    state.closed = true;
+    state.pending.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    use lsp_types::request::Shutdown;
+    use lsp_types::Uri;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{frame, serve};
+
+    // a client connected over TCP to a `serve` thread
+    fn stand_in_client(
+        respond: impl FnMut(Value) -> Option<Vec<u8>> + Send + 'static,
+    ) -> AsyncClient {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        serve(server.try_clone().unwrap(), server, respond);
+
+        stream.set_nonblocking(true).unwrap();
+        let (input, output) = tokio::net::TcpStream::from_std(stream)
+            .unwrap()
+            .into_split();
+        AsyncClient::new(input, output)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        // answer in reverse order, once all requests arrived
+        let mut requests = vec![];
+        let client = stand_in_client(move |request| {
+            requests.push(request);
+            if requests.len() < 3 {
+                return Some(vec![]);
+            }
+
+            let responses = requests.drain(..).rev().map(|request| {
+                frame(json!({"jsonrpc": "2.0", "result": null, "id": request["id"]}))
+            });
+            Some(responses.collect::<Vec<_>>().concat())
+        });
+
+        let (a, b, c) = tokio::join!(
+            client.request::<Shutdown>(None),
+            client.request::<Shutdown>(None),
+            client.request::<Shutdown>(None),
+        );
+
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let client = stand_in_client(|_| None);
+
+        let err = client.request::<Shutdown>(None).await.unwrap_err();
+        assert_eq!(err.to_string(), "server closed the connection");
+
+        // the socket may still take the request, nothing answers it
+        let err = client.request::<Shutdown>(None).await.unwrap_err();
+        assert_eq!(err.to_string(), "server closed the connection");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request() {
+        let client = stand_in_client(|_| Some(vec![]));
+
+        tokio::select! {
+            biased;
+            _ = client.request::<Shutdown>(None) => unreachable!(),
+            _ = std::future::ready(()) => {}
+        }
+
+        assert!(client.inner.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let client = stand_in_client(|msg| {
+            let response =
+                |result| frame(json!({"jsonrpc": "2.0", "result": result, "id": msg["id"]}));
+
+            match msg["method"].as_str() {
+                Some("initialize") => Some(response(json!({
+                    "capabilities": {"referencesProvider": true},
+                }))),
+                // registered before the answer
+                Some("shutdown") => Some(
+                    [
+                        frame(json!({
+                            "jsonrpc": "2.0",
+                            "method": "client/registerCapability",
+                            "params": {"registrations": [
+                                {"id": "hover", "method": "textDocument/hover"},
+                            ]},
+                            "id": "register",
+                        })),
+                        response(Value::Null),
+                    ]
+                    .concat(),
+                ),
+                _ => Some(vec![]),
+            }
+        });
+
+        client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .await
+            .unwrap();
+        assert!(client.capabilities().supports("textDocument/references"));
+        assert!(!client.capabilities().supports("textDocument/hover"));
+
+        client.request::<Shutdown>(None).await.unwrap();
+        assert!(client.capabilities().supports("textDocument/hover"));
+    }
+
+    #[tokio::test]
+    async fn test_facade() {
+        let symbol = json!({
+            "name": "main",
+            "kind": 12,
+            "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 9}},
+            "selectionRange": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 7}},
+            "children": [],
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |request| {
+            let response =
+                frame(json!({"jsonrpc": "2.0", "result": [symbol], "id": request["id"]}));
+            let _ = tx.send(request);
+            Some(response)
+        });
+
+        let uri = Uri::from_str("file:///main.rs").unwrap();
+        let symbols = client.symbols(&uri).await.unwrap();
+
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "main");
+        assert_eq!(rx.recv().unwrap()["method"], "textDocument/documentSymbol");
+    }
+
+    #[tokio::test]
+    async fn test_server_requests() {
+        // ask for the configuration before answering the request
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |msg| match msg.get("method") {
+            Some(_) => Some(frame(json!({
+                "jsonrpc": "2.0",
+                "method": "workspace/configuration",
+                "params": {"items": [{"section": "a"}]},
+                "id": "config",
+            }))),
+            None => {
+                let _ = tx.send(msg);
+                Some(frame(json!({"jsonrpc": "2.0", "result": null, "id": 0})))
+            }
+        });
+
+        client.request::<Shutdown>(None).await.unwrap();
+
+        insta::assert_snapshot!(rx.recv().unwrap(), @r#"{"id":"config","jsonrpc":"2.0","result":[null]}"#);
+    }
+
+    #[tokio::test]
+    async fn test_documents() {
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |msg| {
+            let _ = tx.send(msg);
+            Some(vec![])
+        });
+
+        let uri = Uri::from_str("file:///main.rs").unwrap();
+        client.open(&uri, "fn main() {}").await.unwrap();
+        // open already
+        client.open(&uri, "").await.unwrap();
+        client.change(&uri, "fn main() { 1 }").await.unwrap();
+        assert_eq!(client.document_version(&uri), Some(2));
+        client.close(&uri).await.unwrap();
+        assert!(!client.is_open(&uri));
+        let err = client.change(&uri, "").await.unwrap_err();
+        insta::assert_snapshot!(err, @"document not open: file:///main.rs");
+
+        let received: Vec<_> = rx
+            .iter()
+            .take(3)
+            .map(|msg| {
+                format!(
+                    "{} {}",
+                    msg["method"].as_str().unwrap(),
+                    msg["params"]["textDocument"]
+                )
+            })
+            .collect();
+        insta::assert_debug_snapshot!(received, @r#"
+        [
+            "textDocument/didOpen {\"languageId\":\"\",\"text\":\"fn main() {}\",\"uri\":\"file:///main.rs\",\"version\":1}",
+            "textDocument/didChange {\"uri\":\"file:///main.rs\",\"version\":2}",
+            "textDocument/didClose {\"uri\":\"file:///main.rs\"}",
+        ]
+        "#);
+    }
+
+    #[tokio::test]
+    async fn test_server_closed() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let (input, output) = tokio::io::split(client_stream);
+
+        // the server is gone before the request is even sent
+        drop(server_stream);
+
+        let client = AsyncClient::new(input, output);
+        assert!(client.request::<Shutdown>(None).await.is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+// cancels every request sent through `Client::with_token` at once
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Mutex<TokenState>>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: bool,
+    next_key: u64,
+    callbacks: HashMap<u64, Box<dyn FnOnce() + Send>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        let callbacks = {
+            let mut state = self.inner.lock().unwrap();
+            if state.cancelled {
+                return;
+            }
+
+            state.cancelled = true;
+
+            std::mem::take(&mut state.callbacks)
+        };
+
+        // outside of the lock, cancelling a request takes the client's locks
+        for (_, callback) in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.lock().unwrap().cancelled
+    }
+
+    // the deadlines of all tokens are kept by a single background thread
+    pub fn cancel_after(&self, timeout: Duration) {
+        let timer = Timer::get();
+
+        let mut deadlines = timer.deadlines.lock().unwrap();
+        deadlines.push((Instant::now() + timeout, Arc::downgrade(&self.inner)));
+        timer.changed.notify_one();
+    }
+
+    // `f` is called right away if the token is cancelled already
+    pub(crate) fn on_cancel(&self, f: impl FnOnce() + Send + 'static) -> Option<u64> {
+        let mut state = self.inner.lock().unwrap();
+        if state.cancelled {
+            drop(state);
+            f();
+            return None;
+        }
+
+        let key = state.next_key;
+        state.next_key += 1;
+        state.callbacks.insert(key, Box::new(f));
+
+        Some(key)
+    }
+
+    pub(crate) fn remove(&self, key: u64) {
+        self.inner.lock().unwrap().callbacks.remove(&key);
+    }
+}
+
+#[derive(Default)]
+struct Timer {
+    deadlines: Mutex<Vec<(Instant, Weak<Mutex<TokenState>>)>>,
+    changed: Condvar,
+}
+
+impl Timer {
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+
+        let mut started = false;
+        let timer = TIMER.get_or_init(|| {
+            started = true;
+            Timer::default()
+        });
+
+        if started {
+            std::thread::spawn(move || timer.run());
+        }
+
+        timer
+    }
+
+    fn run(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+
+        loop {
+            // dropped and cancelled tokens need no timer
+            deadlines.retain(|(_, token)| {
+                token
+                    .upgrade()
+                    .is_some_and(|token| !token.lock().unwrap().cancelled)
+            });
+
+            let now = Instant::now();
+            let (expired, waiting) = deadlines
+                .drain(..)
+                .partition(|(deadline, _)| *deadline <= now);
+            *deadlines = waiting;
+
+            if !expired.is_empty() {
+                // outside of the lock, cancelling runs the token's callbacks
+                drop(deadlines);
+                for (_, token) in expired {
+                    if let Some(inner) = token.upgrade() {
+                        CancellationToken { inner }.cancel();
+                    }
+                }
+
+                deadlines = self.deadlines.lock().unwrap();
+                continue;
+            }
+
+            deadlines = match deadlines.iter().map(|(deadline, _)| *deadline).min() {
+                Some(next) => self.changed.wait_timeout(deadlines, next - now).unwrap().0,
+                None => self.changed.wait(deadlines).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn test_callbacks() {
+        let token = CancellationToken::new();
+        let called = Arc::new(AtomicUsize::new(0));
+
+        let count = |called: &Arc<AtomicUsize>| {
+            let called = called.clone();
+            move || {
+                called.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+
+        token.on_cancel(count(&called)).unwrap();
+        let removed = token.on_cancel(count(&called)).unwrap();
+        token.remove(removed);
+
+        token.cancel();
+        token.cancel();
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+
+        // already cancelled, so called right away
+        assert_eq!(token.on_cancel(count(&called)), None);
+        assert_eq!(called.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cancel_after() {
+        let token = CancellationToken::new();
+        token.cancel_after(Duration::from_millis(10));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        token.on_cancel(move || tx.send(()).unwrap());
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_after_many() {
+        let late = CancellationToken::new();
+        late.cancel_after(Duration::from_secs(60));
+
+        let tokens: Vec<_> = (0..100)
+            .map(|i| {
+                let token = CancellationToken::new();
+                token.cancel_after(Duration::from_millis(100 - i));
+                token
+            })
+            .collect();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        tokens[0].on_cancel(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // the earlier ones too, but not the late one
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(tokens.iter().all(CancellationToken::is_cancelled));
+        assert!(!late.is_cancelled());
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use lsp_types::notification::*;
+use lsp_types::request::*;
+use lsp_types::*;
+use serde_json::Value;
+
+use crate::glob::Glob;
+use crate::jsonrpc::{self, ErrorCode};
+
+// the capabilities the server answered `initialize` with, and those it
+// registered since
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    server: ServerCapabilities,
+    // the selector of each static capability, by method
+    providers: HashMap<&'static str, Option<DocumentSelector>>,
+    // by id
+    registrations: HashMap<String, Registered>,
+}
+
+#[derive(Debug, Clone)]
+struct Registered {
+    method: String,
+    // all documents if `None`
+    selector: Option<DocumentSelector>,
+    // the rest of the register options
+    options: Option<Value>,
+}
+
+// the static capability for each method, as named in `ServerCapabilities`
+const PROVIDERS: &[(&str, &str)] = &[
+    (DidOpenTextDocument::METHOD, "textDocumentSync"),
+    (DidChangeTextDocument::METHOD, "textDocumentSync"),
+    (DidCloseTextDocument::METHOD, "textDocumentSync"),
+    (HoverRequest::METHOD, "hoverProvider"),
+    (Completion::METHOD, "completionProvider"),
+    (SignatureHelpRequest::METHOD, "signatureHelpProvider"),
+    (GotoDeclaration::METHOD, "declarationProvider"),
+    (GotoDefinition::METHOD, "definitionProvider"),
+    (GotoTypeDefinition::METHOD, "typeDefinitionProvider"),
+    (GotoImplementation::METHOD, "implementationProvider"),
+    (References::METHOD, "referencesProvider"),
+    (
+        DocumentHighlightRequest::METHOD,
+        "documentHighlightProvider",
+    ),
+    (DocumentSymbolRequest::METHOD, "documentSymbolProvider"),
+    (CodeActionRequest::METHOD, "codeActionProvider"),
+    (CodeLensRequest::METHOD, "codeLensProvider"),
+    (DocumentLinkRequest::METHOD, "documentLinkProvider"),
+    (DocumentColor::METHOD, "colorProvider"),
+    (Formatting::METHOD, "documentFormattingProvider"),
+    (RangeFormatting::METHOD, "documentRangeFormattingProvider"),
+    (OnTypeFormatting::METHOD, "documentOnTypeFormattingProvider"),
+    (Rename::METHOD, "renameProvider"),
+    (FoldingRangeRequest::METHOD, "foldingRangeProvider"),
+    (SelectionRangeRequest::METHOD, "selectionRangeProvider"),
+    (CallHierarchyPrepare::METHOD, "callHierarchyProvider"),
+    (SemanticTokensFullRequest::METHOD, "semanticTokensProvider"),
+    (LinkedEditingRange::METHOD, "linkedEditingRangeProvider"),
+    (MonikerRequest::METHOD, "monikerProvider"),
+    (InlayHintRequest::METHOD, "inlayHintProvider"),
+    (DocumentDiagnosticRequest::METHOD, "diagnosticProvider"),
+    (WorkspaceSymbolRequest::METHOD, "workspaceSymbolProvider"),
+    (ExecuteCommand::METHOD, "executeCommandProvider"),
+];
+
+impl Capabilities {
+    pub fn server(&self) -> &ServerCapabilities {
+        &self.server
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.selectors(method).next().is_some()
+    }
+
+    // filters on a language only rule the document out if its language is known
+    pub fn supports_document(&self, method: &str, uri: &Uri, language: Option<&str>) -> bool {
+        self.selectors(method).any(|selector| match selector {
+            Some(selector) => selector
+                .iter()
+                .any(|filter| filter_matches(filter, uri, language)),
+            None => true,
+        })
+    }
+
+    pub(crate) fn set_server(&mut self, server: ServerCapabilities) {
+        let value = serde_json::to_value(&server).unwrap_or_default();
+
+        self.providers = PROVIDERS
+            .iter()
+            .filter_map(|(method, key)| {
+                let selector = match value.get(*key)? {
+                    Value::Null | Value::Bool(false) => return None,
+                    // a sync kind of NONE
+                    Value::Number(kind) if kind.as_u64() == Some(0) => return None,
+                    provider => provider
+                        .get("documentSelector")
+                        .cloned()
+                        .and_then(|selector| serde_json::from_value(selector).ok()),
+                };
+
+                Some((*method, selector))
+            })
+            .collect();
+        self.server = server;
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        method: &str,
+        params: Option<Value>,
+    ) -> Result<(), jsonrpc::Error> {
+        let params = params.unwrap_or_default();
+
+        match method {
+            RegisterCapability::METHOD => {
+                let params: RegistrationParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+
+                for mut registration in params.registrations {
+                    let selector = registration
+                        .register_options
+                        .as_mut()
+                        .and_then(|options| options.as_object_mut()?.remove("documentSelector"))
+                        .map(serde_json::from_value)
+                        .transpose()
+                        .map_err(invalid_params)?
+                        .flatten();
+
+                    self.registrations.insert(
+                        registration.id,
+                        Registered {
+                            method: registration.method,
+                            selector,
+                            options: registration.register_options,
+                        },
+                    );
+                }
+            }
+            UnregisterCapability::METHOD => {
+                let params: UnregistrationParams =
+                    serde_json::from_value(params).map_err(invalid_params)?;
+
+                for unregistration in params.unregisterations {
+                    self.registrations.remove(&unregistration.id);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // the register options of each registration of `method`
+    pub fn registered<'a>(&'a self, method: &'a str) -> impl Iterator<Item = &'a Value> {
+        self.registrations
+            .values()
+            .filter(move |registered| registered.method == method)
+            .filter_map(|registered| registered.options.as_ref())
+    }
+
+    // `None` for capabilities that apply to all documents
+    fn selectors<'a>(
+        &'a self,
+        method: &'a str,
+    ) -> impl Iterator<Item = Option<DocumentSelector>> + 'a {
+        let registered = self
+            .registrations
+            .values()
+            .filter(move |registered| registered.method == method)
+            .map(|registered| registered.selector.clone());
+
+        self.providers
+            .get(method)
+            .cloned()
+            .into_iter()
+            .chain(registered)
+    }
+}
+
+fn filter_matches(filter: &DocumentFilter, uri: &Uri, language: Option<&str>) -> bool {
+    let language = match (&filter.language, language) {
+        (Some(filter), Some(language)) => filter == language,
+        _ => true,
+    };
+
+    let scheme = filter
+        .scheme
+        .as_ref()
+        .is_none_or(|scheme| uri.scheme().is_some_and(|s| s.as_str() == scheme));
+
+    let pattern = filter.pattern.as_ref().is_none_or(|pattern| {
+        let path = uri.path().as_estr().decode().into_string_lossy();
+
+        // an invalid pattern matches nothing
+        Glob::new(pattern).is_some_and(|glob| glob.is_match(&path))
+    });
+
+    language && scheme && pattern
+}
+
+fn invalid_params(err: serde_json::Error) -> jsonrpc::Error {
+    jsonrpc::Error::new(ErrorCode::InvalidParams, format!("invalid params: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{Client, MockServer};
+
+    #[test]
+    fn test_static() {
+        let mut capabilities = Capabilities::default();
+        capabilities.set_server(
+            serde_json::from_value(json!({
+                "documentSymbolProvider": true,
+                "referencesProvider": false,
+                "textDocumentSync": 0,
+                "declarationProvider": {"documentSelector": [{"pattern": "**/*.rs"}]},
+            }))
+            .unwrap(),
+        );
+
+        let rs = Uri::from_str("file:///src/main.rs").unwrap();
+        let toml = Uri::from_str("file:///Cargo.toml").unwrap();
+
+        assert!(capabilities.supports(DocumentSymbolRequest::METHOD));
+        assert!(capabilities.supports_document(DocumentSymbolRequest::METHOD, &toml, None));
+        assert!(!capabilities.supports(References::METHOD));
+        assert!(!capabilities.supports(DidChangeTextDocument::METHOD));
+        assert!(!capabilities.supports("x/unknown"));
+
+        assert!(capabilities.supports(GotoDeclaration::METHOD));
+        assert!(capabilities.supports_document(GotoDeclaration::METHOD, &rs, None));
+        assert!(!capabilities.supports_document(GotoDeclaration::METHOD, &toml, None));
+    }
+
+    #[test]
+    fn test_dynamic() {
+        let mut capabilities = Capabilities::default();
+        let rs = Uri::from_str("file:///src/main.rs").unwrap();
+        let untitled = Uri::from_str("untitled:Untitled-1").unwrap();
+
+        capabilities
+            .update(
+                RegisterCapability::METHOD,
+                Some(json!({"registrations": [
+                    {
+                        "id": "symbols",
+                        "method": "textDocument/documentSymbol",
+                        "registerOptions": {
+                            "documentSelector": [{"language": "rust", "scheme": "file"}],
+                        },
+                    },
+                    {
+                        "id": "watch",
+                        "method": "workspace/didChangeWatchedFiles",
+                        "registerOptions": {"watchers": []},
+                    },
+                    {"id": "hover", "method": "textDocument/hover"},
+                ]})),
+            )
+            .unwrap();
+
+        let symbols = DocumentSymbolRequest::METHOD;
+        assert!(capabilities.supports(symbols));
+        assert!(capabilities.supports_document(symbols, &rs, Some("rust")));
+        assert!(capabilities.supports_document(symbols, &rs, None));
+        assert!(!capabilities.supports_document(symbols, &rs, Some("toml")));
+        assert!(!capabilities.supports_document(symbols, &untitled, Some("rust")));
+        assert!(capabilities.supports(DidChangeWatchedFiles::METHOD));
+        assert_eq!(
+            capabilities
+                .registered(DidChangeWatchedFiles::METHOD)
+                .collect::<Vec<_>>(),
+            [&json!({"watchers": []})]
+        );
+        assert!(capabilities.supports_document(HoverRequest::METHOD, &untitled, None));
+
+        capabilities
+            .update(
+                UnregisterCapability::METHOD,
+                Some(json!({"unregisterations": [
+                    {"id": "symbols", "method": "textDocument/documentSymbol"},
+                ]})),
+            )
+            .unwrap();
+        assert!(!capabilities.supports(symbols));
+        assert!(capabilities.supports(HoverRequest::METHOD));
+
+        let err = capabilities
+            .update(RegisterCapability::METHOD, Some(json!({})))
+            .unwrap_err();
+        insta::assert_snapshot!(err, @"Error -32602: invalid params: missing field `registrations`");
+    }
+
+    #[test]
+    fn test_client() {
+        let (transport, handle) = MockServer::new().start();
+        let client = Client::with_transport(transport);
+        // registrations are recorded, whoever answers them
+        client.on_request::<RegisterCapability>(|_| Ok(()));
+
+        let root = Uri::from_str("file:///").unwrap();
+        client.initialize(root).unwrap();
+        assert!(client.capabilities().supports(References::METHOD));
+        assert!(!client.capabilities().supports(HoverRequest::METHOD));
+
+        handle
+            .request::<RegisterCapability>(RegistrationParams {
+                registrations: vec![Registration {
+                    id: "hover".to_string(),
+                    method: HoverRequest::METHOD.to_string(),
+                    register_options: Some(json!({"documentSelector": [{"scheme": "file"}]})),
+                }],
+            })
+            .unwrap();
+
+        let capabilities = client.capabilities();
+        let uri = Uri::from_str("file:///src/main.rs").unwrap();
+        assert!(capabilities.supports_document(HoverRequest::METHOD, &uri, None));
+
+        client.shutdown().unwrap();
+    }
+}
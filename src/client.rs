@@ -1,102 +1,1176 @@
+use std::collections::HashMap;
 use std::io::{BufRead, Write};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, RwLock, Weak};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use lsp_types::{notification::Notification, request::Request};
-use serde::Serialize;
+use lsp_types::notification::{Exit, Notification, SetTrace};
+use lsp_types::request::{RegisterCapability, Request, Shutdown, UnregisterCapability};
+use lsp_types::{ServerCapabilities, SetTraceParams, TextDocumentSyncCapability, TraceValue, Uri};
 use serde_json::Value;
 
-use crate::jsonrpc;
+use crate::cancel::CancellationToken;
+use crate::capabilities::Capabilities;
+use crate::codec::FrameError;
+use crate::documents::Documents;
+use crate::handlers::{self, Handlers};
+use crate::jsonrpc::{self, ErrorCode, Id};
+use crate::line_index::PositionEncoding;
+use crate::notifications::Notifications;
+use crate::protocol::{Event, Protocol, ProtocolError};
+use crate::trace::{Entries, TraceLog};
+use crate::transport::{MessageReader, MessageWriter, Transport};
 
+// shared with the reader thread
+struct State {
+    protocol: Protocol,
+    pending: HashMap<Id, Pending>,
+    // why the connection is gone
+    closed: Option<String>,
+    last_read: Instant,
+    writing_since: Option<Instant>,
+    trace: Option<TraceLog>,
+    position_encoding: PositionEncoding,
+    documents: Documents,
+    capabilities: Capabilities,
+}
+
+struct Pending {
+    tx: mpsc::Sender<Result<Value>>,
+    sent: Instant,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            protocol: Protocol::new(),
+            pending: HashMap::new(),
+            closed: None,
+            last_read: Instant::now(),
+            writing_since: None,
+            trace: None,
+            position_encoding: PositionEncoding::default(),
+            documents: Documents::default(),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    // how long the server has not been reading and not been writing, if it is
+    // expected to
+    fn stalls(&self, now: Instant) -> (Option<Duration>, Option<Duration>) {
+        let not_reading = self.writing_since.map(|since| now - since);
+
+        let not_writing = self
+            .pending
+            .values()
+            .map(|pending| pending.sent)
+            .min()
+            .map(|sent| now - sent.max(self.last_read));
+
+        (not_reading, not_writing)
+    }
+}
+
+#[derive(Default)]
+struct Timeouts {
+    default: Option<Duration>,
+    methods: HashMap<&'static str, Option<Duration>>,
+}
+
+impl Timeouts {
+    fn get(&self, method: &str) -> Option<Duration> {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+}
+
+// how long the server gets to answer `shutdown`, and then to exit
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// the watchdog checks at least this far apart, even for tiny `after`s
+const WATCHDOG_MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+// responses are routed to the waiting callers by a background thread, so the
+// client can be cloned and used by many threads. a spawned server is shut
+// down once the last handle is dropped
+#[derive(Clone)]
 pub struct Client {
-    input: Box<dyn BufRead>,
-    output: Box<dyn Write>,
-    request_id_counter: i64,
+    inner: Arc<Inner>,
+    token: Option<CancellationToken>,
+}
+
+struct Inner {
+    state: Arc<Mutex<State>>,
+    writer: Mutex<Box<dyn MessageWriter>>,
+    child: Mutex<Option<Child>>,
+    timeouts: Mutex<Timeouts>,
+    handlers: RwLock<Handlers>,
+    notifications: Notifications,
+    shutdown_grace: Mutex<Duration>,
+    watches_files: AtomicBool,
+    reader: OnceLock<ThreadId>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // don't abandon a server we spawned
+        if self.child.get_mut().unwrap().is_none() {
+            return;
+        }
+
+        // the reader can't read the answer to `shutdown` while it's in here
+        if self.reader.get() == Some(&std::thread::current().id()) {
+            let _ = self.exit();
+        } else {
+            let _ = self.shutdown();
+        }
+    }
 }
 
 impl Client {
-    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+    pub fn new(input: Box<dyn BufRead + Send>, output: Box<dyn Write + Send>) -> Self {
+        Self::with_transport(Transport::streams(input, output))
+    }
+
+    pub fn with_transport(transport: Transport) -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
+
+        let inner = Arc::new(Inner {
+            state: state.clone(),
+            writer: Mutex::new(transport.writer),
+            child: Mutex::new(transport.child),
+            timeouts: Mutex::default(),
+            handlers: RwLock::default(),
+            notifications: Notifications::default(),
+            shutdown_grace: Mutex::new(SHUTDOWN_GRACE),
+            watches_files: AtomicBool::new(false),
+            reader: OnceLock::new(),
+        });
+
+        let reader = std::thread::spawn({
+            let inner = Arc::downgrade(&inner);
+            move || read_loop(transport.reader, state, inner)
+        });
+        let _ = inner.reader.set(reader.thread().id());
+
+        Self { inner, token: None }
+    }
+
+    // cancelled requests fail right away, without waiting for the server
+    pub fn with_token(&self, token: &CancellationToken) -> Self {
         Self {
-            input,
-            output,
-            request_id_counter: 0,
+            inner: self.inner.clone(),
+            token: Some(token.clone()),
         }
     }
 
-    pub fn notify<N: Notification>(&mut self, params: Option<N::Params>) -> Result<()> {
-        let notification = jsonrpc::Notification {
-            jsonrpc: "2.0".to_string(),
-            method: N::METHOD.to_string(),
-            params,
+    pub fn with_child<T>(&self, f: impl FnOnce(&mut Child) -> T) -> Option<T> {
+        self.inner.child.lock().unwrap().as_mut().map(f)
+    }
+
+    // UTF-16 until initialized
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.inner.state.lock().unwrap().position_encoding
+    }
+
+    // for servers initialized without `Client::initialize`
+    pub fn set_position_encoding(&self, encoding: PositionEncoding) {
+        self.inner.state.lock().unwrap().position_encoding = encoding;
+    }
+
+    pub fn is_open(&self, uri: &Uri) -> bool {
+        self.inner.state.lock().unwrap().documents.is_open(uri)
+    }
+
+    pub fn document_version(&self, uri: &Uri) -> Option<i32> {
+        self.inner.state.lock().unwrap().documents.version(uri)
+    }
+
+    pub fn document_text(&self, uri: &Uri) -> Option<String> {
+        let state = self.inner.state.lock().unwrap();
+        state.documents.text(uri).map(str::to_string)
+    }
+
+    pub(crate) fn set_document_sync(&self, capability: Option<&TextDocumentSyncCapability>) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .documents
+            .set_sync(capability);
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.inner.state.lock().unwrap().capabilities.clone()
+    }
+
+    pub(crate) fn set_server_capabilities(&self, capabilities: ServerCapabilities) {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .capabilities
+            .set_server(capabilities);
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn set_watches_files(&self, watches_files: bool) {
+        self.inner
+            .watches_files
+            .store(watches_files, Ordering::SeqCst);
+    }
+
+    pub(crate) fn watches_files(&self) -> bool {
+        self.inner.watches_files.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn sync_documents<N: Notification>(
+        &self,
+        f: impl FnOnce(&mut Documents, PositionEncoding) -> Result<Option<N::Params>>,
+    ) -> Result<()> {
+        self.inner.send(|state| {
+            let encoding = state.position_encoding;
+
+            match f(&mut state.documents, encoding)? {
+                Some(params) => Ok(state.protocol.notify::<N>(Some(params))?),
+                None => Ok(()),
+            }
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().closed.is_some()
+    }
+
+    // handlers run on their own thread, so they may send requests themselves
+    pub fn on_request<R: Request>(
+        &self,
+        handler: impl Fn(R::Params) -> Result<R::Result, jsonrpc::Error> + Send + Sync + 'static,
+    ) {
+        self.inner.handlers.write().unwrap().insert::<R>(handler);
+    }
+
+    // notifications that arrived before anyone subscribed are delivered first, on
+    // the caller's thread. later ones are delivered on the reader thread, so
+    // callbacks must not wait for the client
+    pub fn on_notification<N: Notification>(
+        &self,
+        callback: impl Fn(N::Params) + Send + Sync + 'static,
+    ) {
+        self.inner.notifications.on::<N>(callback);
+    }
+
+    pub fn subscribe<N: Notification>(&self) -> mpsc::Receiver<N::Params> {
+        self.inner.notifications.subscribe::<N>()
+    }
+
+    // the client can't send anything afterwards
+    pub fn shutdown(&self) -> Result<Option<ExitStatus>> {
+        self.inner.shutdown()
+    }
+
+    pub fn set_shutdown_grace(&self, grace: Duration) {
+        *self.inner.shutdown_grace.lock().unwrap() = grace;
+    }
+
+    pub fn set_trace_log(&self, log: Option<TraceLog>) {
+        self.inner.state.lock().unwrap().trace = log;
+    }
+
+    pub fn set_trace(&self, level: TraceValue) -> Result<()> {
+        self.inner.send(|state| {
+            if let Some(trace) = &mut state.trace {
+                trace.set_level(level);
+            }
+
+            Ok(state
+                .protocol
+                .notify::<SetTrace>(Some(SetTraceParams { value: level }))?)
+        })
+    }
+
+    // `None` waits forever
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.inner.timeouts.lock().unwrap().default = timeout;
+    }
+
+    pub fn set_method_timeout<R: Request>(&self, timeout: Option<Duration>) {
+        self.inner
+            .timeouts
+            .lock()
+            .unwrap()
+            .methods
+            .insert(R::METHOD, timeout);
+    }
+
+    // stops when the client is dropped or the connection is closed
+    pub fn watchdog(&self, after: Duration, on_stall: impl Fn(Stall) + Send + 'static) {
+        let inner = Arc::downgrade(&self.inner);
+
+        std::thread::spawn(move || {
+            let mut reported = (false, false);
+
+            loop {
+                std::thread::sleep((after / 4).max(WATCHDOG_MIN_INTERVAL));
+
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+
+                let (not_reading, not_writing) = {
+                    let state = inner.state.lock().unwrap();
+                    if state.closed.is_some() {
+                        return;
+                    }
+
+                    state.stalls(Instant::now())
+                };
+                drop(inner);
+
+                // report every stall only once, until the server recovers
+                let not_reading = not_reading.filter(|stalled| *stalled > after);
+                match not_reading {
+                    Some(stalled) if !reported.0 => on_stall(Stall::NotReading(stalled)),
+                    _ => {}
+                }
+
+                let not_writing = not_writing.filter(|stalled| *stalled > after);
+                match not_writing {
+                    Some(stalled) if !reported.1 => on_stall(Stall::NotWriting(stalled)),
+                    _ => {}
+                }
+
+                reported = (not_reading.is_some(), not_writing.is_some());
+            }
+        });
+    }
+
+    pub fn notify<N: Notification>(&self, params: Option<N::Params>) -> Result<()> {
+        self.inner
+            .send(|state| Ok(state.protocol.notify::<N>(params)?))
+    }
+
+    // errors can be downcast to `jsonrpc::Error` or `RequestTimeout`
+    pub fn request<R: Request>(&self, params: Option<R::Params>) -> Result<R::Result> {
+        let timeout = self.inner.timeouts.lock().unwrap().get(R::METHOD);
+
+        self.request_with_timeout::<R>(params, timeout)
+    }
+
+    pub fn request_with_timeout<R: Request>(
+        &self,
+        params: Option<R::Params>,
+        timeout: Option<Duration>,
+    ) -> Result<R::Result> {
+        self.check_cancelled()?;
+
+        let mut waiters = self
+            .inner
+            .send_requests(|protocol| Ok(vec![protocol.request::<R>(params)?]))?;
+        let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+
+        self.wait::<R>(waiters.remove(0), deadline)
+    }
+
+    pub fn request_batch<R: Request>(
+        &self,
+        params: Vec<Option<R::Params>>,
+    ) -> Result<Vec<Result<R::Result>>> {
+        let timeout = self.inner.timeouts.lock().unwrap().get(R::METHOD);
+        self.check_cancelled()?;
+
+        let waiters = self
+            .inner
+            .send_requests(|protocol| Ok(protocol.request_batch::<R>(params)?))?;
+        let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+
+        Ok(waiters
+            .into_iter()
+            .map(|waiter| self.wait::<R>(waiter, deadline))
+            .collect())
+    }
+
+    // no point in sending a request only to cancel it right away
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.token {
+            Some(token) if token.is_cancelled() => {
+                Err(jsonrpc::Error::new(ErrorCode::RequestCancelled, "request cancelled").into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn wait<R: Request>(
+        &self,
+        (id, rx): (Id, mpsc::Receiver<Result<Value>>),
+        deadline: Option<(Instant, Duration)>,
+    ) -> Result<R::Result> {
+        let cancel = self.token.as_ref().and_then(|token| {
+            let inner = Arc::downgrade(&self.inner);
+            let id = id.clone();
+
+            token.on_cancel(move || {
+                if let Some(inner) = inner.upgrade() {
+                    let _ = cancel(&inner, &id);
+                }
+            })
+        });
+
+        let result = self.inner.recv(id, R::METHOD, rx, deadline);
+
+        if let (Some(token), Some(key)) = (&self.token, cancel) {
+            token.remove(key);
+        }
+
+        serde_json::from_value(result?).context("deserializing response result")
+    }
+}
+
+impl Inner {
+    fn shutdown(&self) -> Result<Option<ExitStatus>> {
+        let grace = *self.shutdown_grace.lock().unwrap();
+
+        let exited = self
+            .send_requests(|protocol| Ok(vec![protocol.request::<Shutdown>(None)?]))
+            .and_then(|mut waiters| {
+                let (id, rx) = waiters.remove(0);
+                let deadline = Instant::now() + grace;
+
+                self.recv(id, Shutdown::METHOD, rx, Some((deadline, grace)))
+            })
+            .and_then(|_| self.send(|state| Ok(state.protocol.notify::<Exit>(None)?)));
+
+        self.close(exited, grace)
+    }
+
+    // without asking the server to shut down first
+    fn exit(&self) -> Result<Option<ExitStatus>> {
+        let grace = *self.shutdown_grace.lock().unwrap();
+        let exited = self.send(|state| Ok(state.protocol.notify::<Exit>(None)?));
+
+        self.close(exited, grace)
+    }
+
+    fn close(&self, exited: Result<()>, grace: Duration) -> Result<Option<ExitStatus>> {
+        // nothing is sent after shutdown, even if the server didn't agree
+        let mut state = self.state.lock().unwrap();
+        state
+            .closed
+            .get_or_insert_with(|| "client was shut down".to_string());
+        drop(state);
+
+        // reap the server even if it misbehaved
+        let status = match self.child.lock().unwrap().take() {
+            Some(mut child) => Some(reap(&mut child, grace)?),
+            None => None,
         };
 
-        self.send(&notification)
+        exited.map(|()| status)
+    }
+
+    // the callers are registered before sending, so the responses can't arrive
+    // first
+    fn send_requests(
+        &self,
+        f: impl FnOnce(&mut Protocol) -> Result<Vec<Id>>,
+    ) -> Result<Vec<(Id, mpsc::Receiver<Result<Value>>)>> {
+        let mut ids = vec![];
+
+        let sent = self.send(|state| {
+            if let Some(reason) = &state.closed {
+                anyhow::bail!("{}", reason);
+            }
+
+            ids = f(&mut state.protocol)?;
+
+            let sent = Instant::now();
+            Ok(ids
+                .iter()
+                .map(|id| {
+                    let (tx, rx) = mpsc::channel();
+                    state.pending.insert(id.clone(), Pending { tx, sent });
+                    (id.clone(), rx)
+                })
+                .collect())
+        });
+
+        if sent.is_err() {
+            let mut state = self.state.lock().unwrap();
+            for id in &ids {
+                state.pending.remove(id);
+            }
+        }
+
+        sent
     }
 
-    pub fn request<R: Request>(&mut self, params: Option<R::Params>) -> Result<R::Result> {
-        let request = jsonrpc::Request {
-            jsonrpc: "2.0".to_string(),
-            method: R::METHOD.to_string(),
-            params,
-            id: self.request_id_counter,
+    fn send<T>(&self, f: impl FnOnce(&mut State) -> Result<T>) -> Result<T> {
+        // the writer is locked first, so messages are written in the order
+        // they were queued
+        let mut writer = self.writer.lock().unwrap();
+
+        let (value, messages, traced) = {
+            let mut state = self.state.lock().unwrap();
+            let value = f(&mut state)?;
+
+            let messages: Vec<_> = std::iter::from_fn(|| state.protocol.poll_transmit()).collect();
+            state.writing_since = Some(Instant::now());
+
+            let traced: Vec<_> = match &mut state.trace {
+                Some(trace) => messages.iter().map(|msg| trace.outgoing(msg)).collect(),
+                None => vec![],
+            };
+
+            (value, messages, traced)
         };
 
-        self.send(&request)?;
+        traced.into_iter().for_each(Entries::write);
 
-        let response: jsonrpc::Response<_> = loop {
-            let response = self.recv()?;
+        let written = messages
+            .iter()
+            .try_for_each(|msg| writer.write_message(msg));
 
-            // check if this is our response
-            if response.get("method").is_none()
-                && response
-                    .get("id")
-                    .is_some_and(|id| id.as_i64() == Some(self.request_id_counter))
-            {
-                break serde_json::from_value(response)?;
+        self.state.lock().unwrap().writing_since = None;
+
+        written.map(|()| value)
+    }
+
+    fn recv(
+        &self,
+        id: Id,
+        method: &str,
+        rx: mpsc::Receiver<Result<Value>>,
+        deadline: Option<(Instant, Duration)>,
+    ) -> Result<Value> {
+        let result = match deadline {
+            None => rx.recv().ok(),
+            Some((deadline, timeout)) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(result) => Some(result),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => None,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        // stop waiting, a late response is dropped by the
+                        // reader thread
+                        let mut state = self.state.lock().unwrap();
+                        state.pending.remove(&id);
+                        state.protocol.forget(&id);
+                        drop(state);
+
+                        // unless it arrived just now
+                        match rx.try_recv() {
+                            Ok(result) => Some(result),
+                            Err(_) => {
+                                return Err(RequestTimeout {
+                                    id,
+                                    method: method.to_string(),
+                                    timeout,
+                                }
+                                .into())
+                            }
+                        }
+                    }
+                }
             }
         };
 
-        self.request_id_counter += 1;
+        result.context("server closed the connection")?
+    }
+}
 
-        response.result.context("getting response result")
+fn respond(inner: Arc<Inner>, request: jsonrpc::Request<Value>) {
+    let handler = inner.handlers.read().unwrap().get(&request.method);
+    let registration = matches!(
+        request.method.as_str(),
+        RegisterCapability::METHOD | UnregisterCapability::METHOD
+    )
+    .then(|| request.params.clone());
+    let result = handlers::handle(handler, &request.method, request.params);
+
+    // nothing to do if the server is gone
+    let _ = inner.send(|state| {
+        // whichever handler answered, the registrations are recorded
+        let result = match (result, registration) {
+            (Ok(value), Some(params)) => state
+                .capabilities
+                .update(&request.method, params)
+                .map(|()| value),
+            (result, _) => result,
+        };
+
+        Ok(state.protocol.respond(request.id, result)?)
+    });
+}
+
+fn reap(child: &mut Child, grace: Duration) -> Result<ExitStatus> {
+    let deadline = Instant::now() + grace;
+
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().context("waiting for server")? {
+            return Ok(status);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
     }
 
-    fn send(&mut self, msg: &impl Serialize) -> Result<()> {
-        let msg = serde_json::to_string(msg)?;
+    child.kill().context("killing server")?;
+    child.wait().context("waiting for server")
+}
+
+fn cancel(inner: &Inner, id: &Id) -> Result<()> {
+    inner.send(|state| {
+        // nothing to do if it was answered already
+        let Some(pending) = state.pending.remove(id) else {
+            return Ok(());
+        };
 
-        let length = msg.as_bytes().len();
-        let msg = &format!("Content-Length: {}\r\n\r\n{}", length, msg);
+        let err = jsonrpc::Error::new(ErrorCode::RequestCancelled, "request cancelled");
+        let _ = pending.tx.send(Err(err.into()));
 
-        self.output
-            .write_all(msg.as_bytes())
-            .context("writing msg to output")
+        Ok(state.protocol.cancel(id)?)
+    })
+}
+
+#[derive(Debug)]
+pub struct RequestTimeout {
+    pub id: Id,
+    pub method: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request {:?} (id {}) timed out after {:?}",
+            self.method, self.id, self.timeout
+        )
     }
+}
 
-    fn recv(&mut self) -> Result<Value> {
-        let mut content_length = None;
+impl std::error::Error for RequestTimeout {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stall {
+    // a write has been blocked for this long
+    NotReading(Duration),
+    // requests are waiting, but nothing was received for this long
+    NotWriting(Duration),
+}
+
+impl std::fmt::Display for Stall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stall::NotReading(stalled) => {
+                write!(f, "server stopped reading, writing for {:?}", stalled)
+            }
+            Stall::NotWriting(stalled) => {
+                write!(f, "server stopped responding, waiting for {:?}", stalled)
+            }
+        }
+    }
+}
 
-        loop {
-            let mut line = String::new();
-            self.input.read_line(&mut line)?;
+fn read_loop(mut reader: Box<dyn MessageReader>, state: Arc<Mutex<State>>, inner: Weak<Inner>) {
+    let reason = loop {
+        let msg = match reader.read_message() {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break "server closed the connection".to_string(),
+            Err(err) => match err.downcast_ref::<FrameError>() {
+                // the broken frame is dropped, the next one can still be read
+                Some(FrameError::Io(_) | FrameError::UnexpectedEof) | None => {
+                    break format!("server closed the connection: {:#}", err)
+                }
+                Some(_) => continue,
+            },
+        };
 
-            let words: Vec<_> = line.split_ascii_whitespace().collect();
+        let mut notifications = vec![];
 
-            match (words.as_slice(), &content_length) {
-                (["Content-Length:", c_length], None) => content_length = Some(c_length.parse()?),
-                (["Content-Type:", _], Some(_)) => {}
-                ([], Some(content_length)) => {
-                    let mut content = Vec::with_capacity(*content_length);
+        let mut state = state.lock().unwrap();
+        state.last_read = Instant::now();
+        let traced = state.trace.as_mut().map(|trace| trace.incoming(&msg));
+        state.protocol.receive_message(&msg);
 
-                    // make sure we don't seek past the current message
-                    let mut bytes_left = *content_length;
-                    while bytes_left > 0 {
-                        let read_bytes = self.input.read_until(b'}', &mut content)?;
-                        bytes_left -= read_bytes;
+        while let Some(event) = state.protocol.poll_event() {
+            let (id, result) = match event {
+                Event::Response { id, result, .. } => (
+                    id,
+                    result
+                        .map_err(|err| anyhow::Error::new(err).context("getting response result")),
+                ),
+                Event::Error(ProtocolError::MalformedResponse(id, err)) => {
+                    let err = ProtocolError::MalformedResponse(id.clone(), err);
+                    (id, Err(err.into()))
+                }
+                Event::Request(request) => {
+                    if let Some(inner) = inner.upgrade() {
+                        std::thread::spawn(move || respond(inner, request));
                     }
-
-                    return serde_json::from_slice(&content).context("deserializing response");
+                    continue;
+                }
+                Event::Notification(notification) => {
+                    notifications.push(notification);
+                    continue;
                 }
-                unexpected => panic!("Got unexpected stdout: {:?}", unexpected),
+                // nothing is waiting for anything else
+                _ => continue,
             };
+
+            if let Some(pending) = state.pending.remove(&id) {
+                let _ = pending.tx.send(result);
+            }
         }
+        drop(state);
+
+        if let Some(traced) = traced {
+            traced.write();
+        }
+
+        // outside of the state lock, callbacks may send messages
+        if let Some(inner) = inner.upgrade() {
+            for notification in notifications {
+                inner.notifications.dispatch(notification);
+            }
+        }
+    };
+
+    // wake up everyone still waiting, the server is gone
+    let mut state = state.lock().unwrap();
+    for (_, pending) in state.pending.drain() {
+        let _ = pending.tx.send(Err(anyhow::anyhow!("{}", reason)));
+    }
+    state.closed.get_or_insert(reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Barrier;
+
+    use lsp_types::notification::{Initialized, LogMessage};
+    use lsp_types::request::{Shutdown, WorkspaceConfiguration};
+    use lsp_types::Uri;
+    use serde_json::json;
+
+    use crate::jsonrpc::MalformedMessage;
+    use crate::test_util::{frame, stand_in_server};
+    use crate::MockServer;
+
+    use super::*;
+
+    fn stand_in_client(mut respond: impl FnMut(Value) -> Vec<u8> + Send + 'static) -> Client {
+        Client::with_transport(stand_in_server(move |msg| Some(respond(msg))))
+    }
+
+    #[test]
+    fn test_handle_is_shareable() {
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<Client>();
+    }
+
+    #[test]
+    fn test_request_batch() {
+        let client = stand_in_client(|_| {
+            [
+                frame(json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {}})),
+                frame(json!([
+                    {"jsonrpc": "2.0", "result": null, "id": 1},
+                    {"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": 0},
+                ])),
+                frame(json!({"jsonrpc": "2.0", "result": null, "id": 2})),
+            ]
+            .concat()
+        });
+
+        let results = client
+            .request_batch::<Shutdown>(vec![None, None, None])
+            .unwrap();
+
+        let results: Vec<_> = results.into_iter().map(|r| r.is_ok()).collect();
+        assert_eq!(results, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        const THREADS: usize = 4;
+
+        // answer in reverse order, once every thread sent its request
+        let mut requests = vec![];
+        let client = stand_in_client(move |request| {
+            requests.push(request);
+            if requests.len() < THREADS {
+                return vec![];
+            }
+
+            requests
+                .drain(..)
+                .rev()
+                .flat_map(|request| {
+                    frame(json!({"jsonrpc": "2.0", "result": request["id"], "id": request["id"]}))
+                })
+                .collect()
+        });
+
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let client = client.clone();
+                let barrier = barrier.clone();
+
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    client.request::<EchoId>(None).unwrap()
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        ids.sort();
+
+        assert_eq!(ids, (0..THREADS as i64).collect::<Vec<_>>());
+    }
+
+    enum EchoId {}
+
+    impl Request for EchoId {
+        type Params = ();
+        type Result = i64;
+        const METHOD: &'static str = "echoId";
+    }
+
+    #[test]
+    fn test_request_error_downcast() {
+        let client = stand_in_client(|_| {
+            frame(json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32801, "message": "Content modified"},
+                "id": 0,
+            }))
+        });
+
+        let err = client.request::<Shutdown>(None).unwrap_err();
+        let err = err.downcast_ref::<jsonrpc::Error>().unwrap();
+
+        assert_eq!(err.code, ErrorCode::ContentModified);
+        assert_eq!(err.message, "Content modified");
+    }
+
+    #[test]
+    fn test_request_malformed_response() {
+        let client = stand_in_client(|_| frame(json!({"jsonrpc": "2.0", "id": 0})));
+
+        let err = client.request::<Shutdown>(None).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::MalformedResponse(
+                Id::Number(0),
+                MalformedMessage::Unknown
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_request_skips_malformed_frames() {
+        let client = stand_in_client(|_| {
+            let msg = r#"{"jsonrpc":"2.0","result":null,"id":0}"#;
+
+            format!(
+                "Content-Type: application/vscode-jsonrpc\r\n\r\n\
+                 content-type: application/vscode-jsonrpc; charset=utf-8\r\ncontent-length: {}\r\n\r\n{}",
+                msg.len(),
+                msg
+            )
+            .into_bytes()
+        });
+
+        // the broken frame is dropped, and the next one is read correctly
+        client.request::<Shutdown>(None).unwrap();
+    }
+
+    #[test]
+    fn test_server_closed() {
+        let client = Client::with_transport(Transport::streams(std::io::empty(), std::io::sink()));
+
+        // the connection may be gone before or after the request is sent
+        let err = client.request::<Shutdown>(None).unwrap_err();
+        assert_eq!(err.to_string(), "server closed the connection");
+
+        let err = client.request::<Shutdown>(None).unwrap_err();
+        assert_eq!(err.to_string(), "server closed the connection");
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // answer shutdown requests late
+        let client = stand_in_client(|request| {
+            if request["method"] == "shutdown" {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+
+            frame(json!({"jsonrpc": "2.0", "result": request["id"], "id": request["id"]}))
+        });
+
+        client.set_method_timeout::<Shutdown>(Some(Duration::from_millis(50)));
+
+        let err = client.request::<Shutdown>(None).unwrap_err();
+        let err = err.downcast_ref::<RequestTimeout>().unwrap();
+        assert_eq!(err.id, Id::Number(0));
+        assert_eq!(err.method, "shutdown");
+
+        let err = client
+            .request_with_timeout::<Shutdown>(None, Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"request "shutdown" (id 1) timed out after 10ms"#
+        );
+
+        // the late responses don't confuse other requests
+        assert_eq!(client.request::<EchoId>(None).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_watchdog_not_writing() {
+        // a server that takes long enough to answer
+        let (transport, _) = MockServer::new()
+            .with_delay::<Shutdown>(Duration::from_millis(500))
+            .start();
+        let client = Client::with_transport(transport);
+
+        let (tx, rx) = mpsc::channel();
+        client.watchdog(Duration::from_millis(50), move |stall| {
+            let _ = tx.send(stall);
+        });
+
+        client.request::<Shutdown>(None).unwrap();
+
+        let stalls: Vec<_> = rx.try_iter().collect();
+        assert_eq!(stalls.len(), 1, "{:?}", stalls);
+        assert!(matches!(stalls[0], Stall::NotWriting(_)));
+    }
+
+    #[test]
+    fn test_watchdog_zero() {
+        let (transport, _) = MockServer::new()
+            .with_delay::<Shutdown>(Duration::from_millis(100))
+            .start();
+        let client = Client::with_transport(transport);
+
+        let (tx, rx) = mpsc::channel();
+        client.watchdog(Duration::ZERO, move |stall| {
+            let _ = tx.send(stall);
+        });
+
+        client.request::<Shutdown>(None).unwrap();
+
+        let stall = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(stall, Stall::NotWriting(_)));
+    }
+
+    #[test]
+    fn test_watchdog_not_reading() {
+        // a server that never reads, so writes block once the pipe is full
+        let (client_input, server_output) = std::io::pipe().unwrap();
+        let (server_input, client_output) = std::io::pipe().unwrap();
+        let client = Client::with_transport(Transport::streams(client_input, client_output));
+
+        let (tx, rx) = mpsc::channel();
+        client.watchdog(Duration::from_millis(20), move |stall| {
+            let _ = tx.send(stall);
+        });
+
+        std::thread::spawn({
+            let client = client.clone();
+            move || {
+                let uri = Uri::from_str("file:///big.rs").unwrap();
+                client.open(&uri, &"a".repeat(1024 * 1024))
+            }
+        });
+
+        let stall = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(stall, Stall::NotReading(_)));
+
+        // let the write fail
+        drop((server_input, server_output));
+    }
+
+    #[test]
+    fn test_cancel() {
+        // never answer shutdown requests, only their cancels
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |msg| {
+            tx.send(msg.clone()).unwrap();
+
+            match msg["method"].as_str() {
+                Some("$/cancelRequest") => frame(json!({
+                    "jsonrpc": "2.0",
+                    "error": {"code": -32800, "message": "cancelled"},
+                    "id": msg["params"]["id"],
+                })),
+                Some("echoId") => {
+                    frame(json!({"jsonrpc": "2.0", "result": msg["id"], "id": msg["id"]}))
+                }
+                _ => vec![],
+            }
+        });
+
+        let token = CancellationToken::new();
+        token.cancel_after(Duration::from_millis(20));
+
+        let err = client
+            .with_token(&token)
+            .request::<Shutdown>(None)
+            .unwrap_err();
+        let err = err.downcast_ref::<jsonrpc::Error>().unwrap();
+        assert_eq!(err.code, ErrorCode::RequestCancelled);
+
+        // requests with a cancelled token are not even sent
+        let err = client
+            .with_token(&token)
+            .request::<Shutdown>(None)
+            .unwrap_err();
+        assert!(err.is::<jsonrpc::Error>());
+
+        // other handles are not affected
+        assert_eq!(client.request::<EchoId>(None).unwrap(), 1);
+
+        let cancels: Vec<_> = rx
+            .try_iter()
+            .filter(|msg| msg["method"] == "$/cancelRequest")
+            .map(|msg| msg["params"].clone())
+            .collect();
+        assert_eq!(cancels, vec![json!({"id": 0})]);
+    }
+
+    #[test]
+    fn test_server_requests() {
+        // send requests to the client once it is initialized
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |msg| {
+            if msg["method"] != "initialized" {
+                tx.send(msg).unwrap();
+                return vec![];
+            }
+
+            [
+                json!({"method": "workspace/configuration", "params": {"items": [{}]}, "id": 0}),
+                json!({"method": "window/workDoneProgress/create", "params": {"token": "t"}, "id": 1}),
+                json!({"method": "x/unknown", "id": 2}),
+            ]
+            .into_iter()
+            .flat_map(|mut request| {
+                request["jsonrpc"] = json!("2.0");
+                frame(request)
+            })
+            .collect()
+        });
+
+        client.on_request::<WorkspaceConfiguration>(|params| {
+            Ok(vec![json!({"checkOnSave": false}); params.items.len()])
+        });
+        client.notify::<Initialized>(None).unwrap();
+
+        let mut responses: Vec<_> = (0..3)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap().to_string())
+            .collect();
+        responses.sort();
+
+        insta::assert_debug_snapshot!(responses, @r#"
+        [
+            "{\"error\":{\"code\":-32601,\"message\":\"unhandled method \\\"x/unknown\\\"\"},\"id\":2,\"jsonrpc\":\"2.0\"}",
+            "{\"id\":0,\"jsonrpc\":\"2.0\",\"result\":[{\"checkOnSave\":false}]}",
+            "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":null}",
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_notifications() {
+        // log while answering, before anyone subscribed
+        let client = stand_in_client(|request| {
+            [
+                frame(json!({
+                    "jsonrpc": "2.0",
+                    "method": "window/logMessage",
+                    "params": {"type": 3, "message": request["id"].to_string()},
+                })),
+                frame(json!({"jsonrpc": "2.0", "result": request["id"], "id": request["id"]})),
+            ]
+            .concat()
+        });
+
+        client.request::<EchoId>(None).unwrap();
+
+        let logs = client.subscribe::<LogMessage>();
+        client.request::<EchoId>(None).unwrap();
+
+        let messages: Vec<_> = logs.iter().take(2).map(|params| params.message).collect();
+        assert_eq!(messages, vec!["0", "1"]);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (tx, rx) = mpsc::channel();
+        let client = stand_in_client(move |msg| {
+            let _ = tx.send(msg["method"].clone());
+            frame(json!({"jsonrpc": "2.0", "result": null, "id": msg["id"]}))
+        });
+
+        assert_eq!(client.shutdown().unwrap(), None);
+
+        let methods: Vec<_> = rx.iter().take(2).collect();
+        assert_eq!(methods, vec!["shutdown", "exit"]);
+
+        let err = client.request::<Shutdown>(None).unwrap_err();
+        assert_eq!(err.to_string(), "client was shut down");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shutdown_kills_hung_server() {
+        let transport = Transport::stdio(std::process::Command::new("sleep").arg("60")).unwrap();
+        let client = Client::with_transport(transport);
+        client.set_shutdown_grace(Duration::from_millis(50));
+
+        let err = client.shutdown().unwrap_err();
+        assert!(err.is::<RequestTimeout>());
+        assert!(client.is_closed());
+
+        // already reaped
+        assert!(client.with_child(|_| ()).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_drop_on_reader() {
+        // logs something once the client said anything, and exits on the next
+        // message
+        let log =
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#;
+        let script = format!(
+            "head -c 1 >/dev/null; printf 'Content-Length: {}\\r\\n\\r\\n%s' '{}'; head -c 1 >/dev/null",
+            log.len(),
+            log
+        );
+        let transport = Transport::stdio(std::process::Command::new("sh").args(["-c", &script]));
+        let client = Client::with_transport(transport.unwrap());
+        client.set_shutdown_grace(Duration::from_secs(10));
+
+        // the last handle is dropped on the reader thread
+        let (tx, rx) = mpsc::channel::<()>();
+        let last = Mutex::new(Some(client.clone()));
+        client.on_notification::<LogMessage>(move |_| {
+            let _ = &tx;
+            last.lock().unwrap().take();
+        });
+        client.notify::<Initialized>(None).unwrap();
+        drop(client);
+
+        let started = Instant::now();
+        assert!(rx.recv().is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
     }
 }
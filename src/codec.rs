@@ -0,0 +1,387 @@
+use std::io::Read;
+
+// a header block larger than this is surely garbage
+const MAX_HEADER_LENGTH: usize = 8 * 1024;
+
+// a larger body is rather a hostile or broken peer than a real message
+const MAX_BODY_LENGTH: usize = 64 * 1024 * 1024;
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+pub fn encode(msg: &str) -> Vec<u8> {
+    let mut frame = format!("Content-Length: {}\r\n\r\n", msg.len()).into_bytes();
+    frame.extend_from_slice(msg.as_bytes());
+
+    frame
+}
+
+// after an error the offending header block is dropped, so decoding can
+// continue with the next frame
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn decode(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(header_length) = self
+            .buf
+            .windows(HEADER_END.len())
+            .position(|w| w == HEADER_END)
+        else {
+            if self.buf.len() > MAX_HEADER_LENGTH {
+                self.buf.clear();
+                return Err(FrameError::HeaderTooLarge);
+            }
+
+            return Ok(None);
+        };
+
+        let body_start = header_length + HEADER_END.len();
+
+        let header = match parse_header(&self.buf[..header_length]) {
+            Ok(header) => header,
+            Err(err) => {
+                self.buf.drain(..body_start);
+                return Err(err);
+            }
+        };
+
+        // can't overflow, the length is capped at `MAX_BODY_LENGTH`
+        let body_end = body_start + header.content_length;
+        if self.buf.len() < body_end {
+            return Ok(None);
+        }
+
+        let body = self.buf[body_start..body_end].to_vec();
+        self.buf.drain(..body_end);
+
+        // the body is dropped as well, since its length is known
+        match header.charset {
+            Some(charset) if !is_utf8(&charset) => Err(FrameError::UnsupportedCharset(charset)),
+            _ => Ok(Some(body)),
+        }
+    }
+}
+
+struct Header {
+    content_length: usize,
+    charset: Option<String>,
+}
+
+fn parse_header(header: &[u8]) -> Result<Header, FrameError> {
+    let header = std::str::from_utf8(header)
+        .map_err(|_| FrameError::InvalidHeader(String::from_utf8_lossy(header).into_owned()))?;
+
+    let mut content_length = None;
+    let mut charset = None;
+
+    for line in header.split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(FrameError::InvalidHeader(line.to_string()));
+        };
+
+        let (name, value) = (name.trim(), value.trim());
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let length = value
+                .parse()
+                .ok()
+                .filter(|length| *length <= MAX_BODY_LENGTH)
+                .ok_or_else(|| FrameError::InvalidContentLength(value.to_string()))?;
+
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            charset = parse_charset(value);
+        }
+    }
+
+    Ok(Header {
+        content_length: content_length.ok_or(FrameError::MissingContentLength)?,
+        charset,
+    })
+}
+
+fn parse_charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn is_utf8(charset: &str) -> bool {
+    // "utf8" is accepted for backwards compatibility
+    charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("utf8")
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: Decoder,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            decoder: Decoder::new(),
+        }
+    }
+
+    // `None` if the reader reached EOF between frames
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut buf = [0; 8 * 1024];
+
+        loop {
+            if let Some(frame) = self.decoder.decode()? {
+                return Ok(Some(frame));
+            }
+
+            let read = self.reader.read(&mut buf).map_err(FrameError::Io)?;
+            if read == 0 {
+                return match self.decoder.is_empty() {
+                    true => Ok(None),
+                    false => Err(FrameError::UnexpectedEof),
+                };
+            }
+
+            self.decoder.feed(&buf[..read]);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    MissingContentLength,
+    InvalidContentLength(String),
+    InvalidHeader(String),
+    UnsupportedCharset(String),
+    HeaderTooLarge,
+    UnexpectedEof,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::MissingContentLength => write!(f, "missing Content-Length header"),
+            FrameError::InvalidContentLength(length) => {
+                write!(f, "invalid Content-Length: {:?}", length)
+            }
+            FrameError::InvalidHeader(header) => write!(f, "invalid header: {:?}", header),
+            FrameError::UnsupportedCharset(charset) => {
+                write!(f, "unsupported charset: {:?}", charset)
+            }
+            FrameError::HeaderTooLarge => write!(f, "header is too large"),
+            FrameError::UnexpectedEof => write!(f, "unexpected EOF in the middle of a frame"),
+            FrameError::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut Decoder) -> Vec<Result<String, String>> {
+        let mut frames = vec![];
+        loop {
+            match decoder.decode() {
+                Ok(Some(frame)) => frames.push(Ok(String::from_utf8(frame).unwrap())),
+                Ok(None) => return frames,
+                Err(err) => frames.push(Err(err.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        insta::assert_snapshot!(
+            String::from_utf8(encode(r#"{"a": "ü"}"#)).unwrap(),
+            @r#"
+        Content-Length: 11
+
+        {"a": "ü"}
+        "#
+        );
+    }
+
+    #[test]
+    fn test_decode_split_at_every_byte() {
+        let stream = [encode(r#"{"a": 1}"#), encode(r#"{"b": "ü"}"#), encode("[]")].concat();
+
+        for split in 0..=stream.len() {
+            let mut decoder = Decoder::new();
+            let mut frames = vec![];
+
+            for chunk in [&stream[..split], &stream[split..]] {
+                decoder.feed(chunk);
+                frames.extend(decode_all(&mut decoder));
+            }
+
+            assert_eq!(
+                frames,
+                vec![
+                    Ok(r#"{"a": 1}"#.to_string()),
+                    Ok(r#"{"b": "ü"}"#.to_string()),
+                    Ok("[]".to_string()),
+                ],
+                "split at {}",
+                split
+            );
+            assert!(decoder.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte() {
+        let stream = [encode("{}"), encode(r#"{"c": "}}}"}"#)].concat();
+
+        let mut decoder = Decoder::new();
+        let mut frames = vec![];
+        for byte in stream {
+            decoder.feed(&[byte]);
+            frames.extend(decode_all(&mut decoder));
+        }
+
+        insta::assert_debug_snapshot!(frames, @r#"
+        [
+            Ok(
+                "{}",
+            ),
+            Ok(
+                "{\"c\": \"}}}\"}",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_decode_headers() {
+        let mut decoder = Decoder::new();
+        decoder.feed(
+            b"Content-Type: application/vscode-jsonrpc; charset=utf-8\r\nContent-Length: 2\r\n\r\n{}\
+              content-length:2\r\ncontent-type: application/vscode-jsonrpc; charset=\"UTF8\"\r\n\r\n[]\
+              X-Custom: ignored\r\nCONTENT-LENGTH:   4  \r\n\r\nnull",
+        );
+
+        insta::assert_debug_snapshot!(decode_all(&mut decoder), @r#"
+        [
+            Ok(
+                "{}",
+            ),
+            Ok(
+                "[]",
+            ),
+            Ok(
+                "null",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_decode_recovers_from_errors() {
+        let mut decoder = Decoder::new();
+        decoder.feed(
+            b"Content-Length: abc\r\n\r\n\
+              Content-Type: application/vscode-jsonrpc\r\n\r\n\
+              Content-Length: 2\r\nContent-Type: text/plain; charset=latin1\r\n\r\n{}\
+              garbage\r\n\r\n\
+              Content-Length: 2\r\n\r\n{}",
+        );
+
+        insta::assert_debug_snapshot!(decode_all(&mut decoder), @r#"
+        [
+            Err(
+                "invalid Content-Length: \"abc\"",
+            ),
+            Err(
+                "missing Content-Length header",
+            ),
+            Err(
+                "unsupported charset: \"latin1\"",
+            ),
+            Err(
+                "invalid header: \"garbage\"",
+            ),
+            Ok(
+                "{}",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_decode_header_too_large() {
+        let mut decoder = Decoder::new();
+        decoder.feed(&vec![b'a'; MAX_HEADER_LENGTH + 1]);
+
+        assert!(matches!(decoder.decode(), Err(FrameError::HeaderTooLarge)));
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_decode_content_length_too_large() {
+        let mut decoder = Decoder::new();
+        decoder.feed(
+            b"Content-Length: 18446744073709551615\r\n\r\n\
+              Content-Length: 67108865\r\n\r\n\
+              Content-Length: 2\r\n\r\n{}",
+        );
+
+        insta::assert_debug_snapshot!(decode_all(&mut decoder), @r#"
+        [
+            Err(
+                "invalid Content-Length: \"18446744073709551615\"",
+            ),
+            Err(
+                "invalid Content-Length: \"67108865\"",
+            ),
+            Ok(
+                "{}",
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let stream = [encode("{}"), encode("[]")].concat();
+
+        let mut reader = FrameReader::new(&stream[..]);
+        assert_eq!(reader.read_frame().unwrap(), Some(b"{}".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(b"[]".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), None);
+
+        let mut reader = FrameReader::new(&stream[..stream.len() - 1]);
+        assert_eq!(reader.read_frame().unwrap(), Some(b"{}".to_vec()));
+        assert!(matches!(
+            reader.read_frame(),
+            Err(FrameError::UnexpectedEof)
+        ));
+    }
+}
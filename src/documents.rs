@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use lsp_types::*;
+
+use crate::line_index::{LineIndex, PositionEncoding};
+
+// the text and version the server last got for each open document
+pub(crate) struct Documents {
+    documents: HashMap<Uri, Document>,
+    sync: TextDocumentSyncKind,
+}
+
+struct Document {
+    text: String,
+    version: i32,
+}
+
+impl Default for Documents {
+    fn default() -> Self {
+        Self {
+            documents: HashMap::new(),
+            // until the server says otherwise, the whole text always works
+            sync: TextDocumentSyncKind::FULL,
+        }
+    }
+}
+
+impl Documents {
+    pub(crate) fn set_sync(&mut self, capability: Option<&TextDocumentSyncCapability>) {
+        self.sync = match capability {
+            Some(TextDocumentSyncCapability::Kind(kind)) => *kind,
+            Some(TextDocumentSyncCapability::Options(options)) => {
+                options.change.unwrap_or(TextDocumentSyncKind::NONE)
+            }
+            None => TextDocumentSyncKind::NONE,
+        };
+    }
+
+    pub(crate) fn is_open(&self, uri: &Uri) -> bool {
+        self.documents.contains_key(uri)
+    }
+
+    pub(crate) fn version(&self, uri: &Uri) -> Option<i32> {
+        self.documents.get(uri).map(|document| document.version)
+    }
+
+    pub(crate) fn text(&self, uri: &Uri) -> Option<&str> {
+        self.documents
+            .get(uri)
+            .map(|document| document.text.as_str())
+    }
+
+    pub(crate) fn open(&mut self, uri: &Uri, text: &str) -> Option<DidOpenTextDocumentParams> {
+        if self.is_open(uri) {
+            return None;
+        }
+
+        let document = Document {
+            text: text.to_string(),
+            version: 1,
+        };
+
+        let params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: String::new(),
+                version: document.version,
+                text: document.text.clone(),
+            },
+        };
+
+        self.documents.insert(uri.clone(), document);
+
+        Some(params)
+    }
+
+    // `None` if the text didn't change, or the server doesn't want changes
+    pub(crate) fn change(
+        &mut self,
+        uri: &Uri,
+        text: &str,
+        encoding: PositionEncoding,
+    ) -> Result<Option<DidChangeTextDocumentParams>> {
+        let Some(document) = self.documents.get_mut(uri) else {
+            anyhow::bail!("document not open: {}", uri.as_str());
+        };
+        if document.text == text {
+            return Ok(None);
+        }
+
+        let change = match self.sync {
+            TextDocumentSyncKind::INCREMENTAL => diff(&document.text, text, encoding),
+            _ => TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            },
+        };
+
+        document.text = text.to_string();
+        document.version += 1;
+
+        if self.sync == TextDocumentSyncKind::NONE {
+            return Ok(None);
+        }
+
+        Ok(Some(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: document.version,
+            },
+            content_changes: vec![change],
+        }))
+    }
+
+    // the ranges of `edits` are all in the current text, see `sort_edits`
+    pub(crate) fn edit(
+        &mut self,
+        uri: &Uri,
+        edits: &[&TextEdit],
+        encoding: PositionEncoding,
+    ) -> Result<Option<DidChangeTextDocumentParams>> {
+        let Some(text) = self.text(uri) else {
+            anyhow::bail!("document not open: {}", uri.as_str());
+        };
+        let index = LineIndex::new(text);
+        let old = index.text();
+
+        let mut text = String::new();
+        let mut last = 0;
+        for edit in edits {
+            let start = index.offset(edit.range.start, encoding).max(last);
+            let end = index.offset(edit.range.end, encoding).max(start);
+
+            text += &old[last..start];
+            text += &edit.new_text;
+            last = end;
+        }
+        text += &old[last..];
+
+        self.change(uri, &text, encoding)
+    }
+
+    pub(crate) fn close(&mut self, uri: &Uri) -> Option<DidCloseTextDocumentParams> {
+        self.documents.remove(uri)?;
+
+        Some(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+        })
+    }
+}
+
+// `edits` in the order they apply in, inserts at the same position in the
+// order they were given
+pub(crate) fn sort_edits(edits: &[TextEdit]) -> Result<Vec<&TextEdit>> {
+    if let Some(edit) = edits.iter().find(|edit| edit.range.start > edit.range.end) {
+        anyhow::bail!("edit ends before it starts: {}", range(&edit.range));
+    }
+
+    // inserts go before a replace starting at the same position
+    let mut edits: Vec<_> = edits.iter().collect();
+    edits.sort_by_key(|edit| (edit.range.start, edit.range.end));
+
+    if let Some(pair) = edits
+        .windows(2)
+        .find(|pair| pair[1].range.start < pair[0].range.end)
+    {
+        anyhow::bail!(
+            "edits overlap: {} and {}",
+            range(&pair[0].range),
+            range(&pair[1].range)
+        );
+    }
+
+    Ok(edits)
+}
+
+fn range(range: &Range) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        range.start.line, range.start.character, range.end.line, range.end.character
+    )
+}
+
+// a single change replacing the part of `old` that differs from `new`
+fn diff(old: &str, new: &str, encoding: PositionEncoding) -> TextDocumentContentChangeEvent {
+    let mut prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(old.len().min(new.len()), |((i, _), _)| i);
+
+    // a change can't start or end between `\r` and `\n`, positions there
+    // are at the end of the line
+    if old[..prefix].ends_with('\r') {
+        prefix -= 1;
+    }
+
+    let mut suffix = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum::<usize>();
+
+    if old[..old.len() - suffix].ends_with('\r') && old[old.len() - suffix..].starts_with('\n') {
+        suffix -= 1;
+    }
+
+    let index = LineIndex::new(old);
+    let range = Range::new(
+        index.position(prefix, encoding),
+        index.position(old.len() - suffix, encoding),
+    );
+
+    TextDocumentContentChangeEvent {
+        range: Some(range),
+        range_length: None,
+        text: new[prefix..new.len() - suffix].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn apply(
+        text: &str,
+        change: &TextDocumentContentChangeEvent,
+        encoding: PositionEncoding,
+    ) -> String {
+        let Some(range) = change.range else {
+            return change.text.clone();
+        };
+
+        let index = LineIndex::new(text);
+        let (start, end) = (
+            index.offset(range.start, encoding),
+            index.offset(range.end, encoding),
+        );
+
+        format!("{}{}{}", &text[..start], change.text, &text[end..])
+    }
+
+    #[test]
+    fn test_diff() {
+        let cases = [
+            ("fn main() {}", "fn main() { 1 }"),
+            ("aé😀b", "aé😀😀b"),
+            ("λ\r\nb", "λ\nb"),
+            ("a\r\nb", "a\r\r\nb"),
+            ("a\nb", "a\r\nb"),
+            ("same", "same"),
+            ("", "new"),
+            ("old", ""),
+        ];
+
+        for (old, new) in cases {
+            for encoding in PositionEncoding::ALL {
+                let change = diff(old, new, encoding);
+                assert_eq!(
+                    apply(old, &change, encoding),
+                    new,
+                    "{:?} with {:?}",
+                    change,
+                    encoding
+                );
+            }
+        }
+
+        let change = diff("aé😀b\nc", "aé😀X\nc", PositionEncoding::Utf16);
+        let range = change.range.unwrap();
+        assert_eq!(
+            (range.start, range.end),
+            (Position::new(0, 4), Position::new(0, 5))
+        );
+        assert_eq!(change.text, "X");
+    }
+
+    #[test]
+    fn test_edit() {
+        let uri = Uri::from_str("file:///a.rs").unwrap();
+        let mut documents = Documents::default();
+        let encoding = PositionEncoding::Utf16;
+        documents.open(&uri, "xy\nλz");
+
+        let insert = |line, character, text: &str| {
+            TextEdit::new(
+                Range::new(
+                    Position::new(line, character),
+                    Position::new(line, character),
+                ),
+                text.to_string(),
+            )
+        };
+        let replace = |start, end, text: &str| {
+            TextEdit::new(
+                Range::new(Position::new(1, start), Position::new(1, end)),
+                text.to_string(),
+            )
+        };
+
+        // inserts at the same position keep their order
+        let edits = [
+            replace(1, 2, "Z"),
+            insert(0, 1, "A"),
+            insert(0, 1, "B"),
+            replace(0, 1, "L"),
+        ];
+        let edits = sort_edits(&edits).unwrap();
+        documents.edit(&uri, &edits, encoding).unwrap();
+        assert_eq!(documents.text(&uri), Some("xABy\nLZ"));
+
+        // past the end of the text
+        let edits = [insert(0, 9, "!"), insert(7, 0, "?")];
+        let edits = sort_edits(&edits).unwrap();
+        documents.edit(&uri, &edits, encoding).unwrap();
+        assert_eq!(documents.text(&uri), Some("xABy!\nLZ?"));
+
+        // whichever order an insert and a replace at the same start come in
+        let other = Uri::from_str("file:///b.rs").unwrap();
+        for edits in [
+            [insert(1, 1, "I"), replace(1, 3, "X")],
+            [replace(1, 3, "X"), insert(1, 1, "I")],
+        ] {
+            documents.open(&other, "\nabcdef");
+            let edits = sort_edits(&edits).unwrap();
+            documents.edit(&other, &edits, encoding).unwrap();
+            assert_eq!(documents.text(&other), Some("\naIXdef"));
+            documents.close(&other);
+        }
+
+        let err = sort_edits(&[replace(0, 2, "a"), replace(1, 1, "b")]).unwrap_err();
+        insta::assert_snapshot!(err, @"edits overlap: 1:0-1:2 and 1:1-1:1");
+
+        let err = sort_edits(&[replace(2, 1, "a")]).unwrap_err();
+        insta::assert_snapshot!(err, @"edit ends before it starts: 1:2-1:1");
+    }
+
+    #[test]
+    fn test_documents() {
+        let uri = Uri::from_str("file:///a.rs").unwrap();
+        let mut documents = Documents::default();
+        let encoding = PositionEncoding::Utf16;
+
+        assert!(documents.open(&uri, "a").is_some());
+        assert!(documents.open(&uri, "b").is_none());
+        assert_eq!(documents.text(&uri), Some("a"));
+
+        // full sync until the server says otherwise
+        let change = documents.change(&uri, "ab", encoding).unwrap().unwrap();
+        assert_eq!(change.text_document.version, 2);
+        assert_eq!(change.content_changes[0].range, None);
+        assert!(documents.change(&uri, "ab", encoding).unwrap().is_none());
+
+        documents.set_sync(Some(&TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::NONE,
+        )));
+        assert!(documents.change(&uri, "abc", encoding).unwrap().is_none());
+        assert_eq!(documents.version(&uri), Some(3));
+
+        assert!(documents.close(&uri).is_some());
+        assert!(documents.close(&uri).is_none());
+        assert!(!documents.is_open(&uri));
+        let err = documents.change(&uri, "abc", encoding).unwrap_err();
+        insta::assert_snapshot!(err, @"document not open: file:///a.rs");
+    }
+}
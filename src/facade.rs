@@ -2,136 +2,453 @@ use anyhow::Result;
 use lsp_types::{notification::*, request::*, *};
 use serde_json::json;
 
+use crate::documents::sort_edits;
+use crate::line_index::PositionEncoding;
+use crate::{CapabilitiesBuilder, InitializeBuilder};
+
 impl crate::Client {
-    pub fn open(&mut self, uri: &Uri, text: &str) -> Result<()> {
-        self.notify::<DidOpenTextDocument>(
-            serde_json::from_value(json!(
-                {
-                    "textDocument": {
-                        "uri": uri,
-                        "languageId": "",
-                        "version": 1,
-                        "text": text
-                    }
-                }
-            ))
-            .unwrap(),
-        )
+    pub fn open(&self, uri: &Uri, text: &str) -> Result<()> {
+        self.sync_documents::<DidOpenTextDocument>(|documents, _| Ok(documents.open(uri, text)))
     }
 
-    pub fn references(&mut self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
-        let references = self.request::<References>(
-            serde_json::from_value(json!(
-                {
-                    "textDocument": {
-                        "uri": uri,
-                    },
-                    "position": symbol.selection_range.start,
-                    "context": {
-                        "includeDeclaration": false
-                    }
-                }
-            ))
-            .unwrap(),
-        )?;
+    // only what changed is sent if the server syncs incrementally
+    pub fn change(&self, uri: &Uri, text: &str) -> Result<()> {
+        self.sync_documents::<DidChangeTextDocument>(|documents, encoding| {
+            documents.change(uri, text, encoding)
+        })
+    }
 
-        Ok(references
-            .unwrap_or_default()
-            .into_iter()
-            .map(|r| r.uri)
-            .filter(|r| r != uri)
-            .collect())
-    }
-
-    pub fn definitions(&mut self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
-        let definitions = self.request::<GotoDefinition>(
-            serde_json::from_value(json!(
-                {
-                    "textDocument": {
-                        "uri": uri,
-                    },
-                    "position": symbol.selection_range.start,
-                }
-            ))
-            .unwrap(),
-        )?;
-
-        let definitions = match definitions {
-            Some(GotoDefinitionResponse::Scalar(location)) => vec![location.uri],
-            Some(GotoDefinitionResponse::Array(vec)) => vec.into_iter().map(|l| l.uri).collect(),
-            Some(GotoDefinitionResponse::Link(vec)) => {
-                vec.into_iter().map(|l| l.target_uri).collect()
-            }
-            None => vec![],
-        };
+    // ranges are in the negotiated position encoding
+    pub fn edit(&self, uri: &Uri, edits: &[TextEdit]) -> Result<()> {
+        let edits = sort_edits(edits)?;
 
-        Ok(definitions)
+        self.sync_documents::<DidChangeTextDocument>(|documents, encoding| {
+            documents.edit(uri, &edits, encoding)
+        })
     }
 
-    pub fn symbols(&mut self, uri: &Uri) -> Result<Vec<DocumentSymbol>> {
-        let symbols = self.request::<DocumentSymbolRequest>(
-            serde_json::from_value(json!(
-                {
-                    "textDocument": {
-                        "uri": uri
-                    },
-                }
-            ))
-            .unwrap(),
-        )?;
-
-        let symbols = match symbols {
-            Some(DocumentSymbolResponse::Nested(vec)) => {
-                let mut symbols = vec![];
-                let mut queue = vec;
-
-                // flatten nested document symbols
-                while let Some(symbol) = queue.pop() {
-                    symbols.push(symbol.clone());
-                    if let Some(children) = symbol.children {
-                        queue.extend(children);
-                    }
-                }
+    pub fn close(&self, uri: &Uri) -> Result<()> {
+        self.sync_documents::<DidCloseTextDocument>(|documents, _| Ok(documents.close(uri)))
+    }
+
+    pub fn references(&self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
+        let references = self.request::<References>(references_params(uri, symbol))?;
+
+        Ok(references_result(uri, references))
+    }
 
-                symbols
+    pub fn definitions(&self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
+        let definitions = self.request::<GotoDefinition>(definitions_params(uri, symbol))?;
+
+        Ok(definitions_result(definitions))
+    }
+
+    pub fn symbols(&self, uri: &Uri) -> Result<Vec<DocumentSymbol>> {
+        let symbols = self.request::<DocumentSymbolRequest>(symbols_params(uri))?;
+
+        Ok(symbols_result(symbols))
+    }
+
+    pub fn initialize(&self, uri: Uri) -> Result<ServerCapabilities> {
+        self.initialize_with(InitializeBuilder::new(uri).build())
+    }
+
+    pub fn initialize_with(&self, params: InitializeParams) -> Result<ServerCapabilities> {
+        let params = initialize_params(params, self.watches_files());
+        let response = self.request::<Initialize>(params)?;
+        self.set_position_encoding(PositionEncoding::negotiated(&response.capabilities));
+        self.set_document_sync(response.capabilities.text_document_sync.as_ref());
+        self.set_server_capabilities(response.capabilities.clone());
+
+        self.notify::<Initialized>(None)?;
+
+        Ok(response.capabilities)
+    }
+}
+
+#[cfg(feature = "async")]
+impl crate::AsyncClient {
+    pub async fn open(&self, uri: &Uri, text: &str) -> Result<()> {
+        self.sync_documents::<DidOpenTextDocument>(|documents, _| Ok(documents.open(uri, text)))
+            .await
+    }
+
+    pub async fn change(&self, uri: &Uri, text: &str) -> Result<()> {
+        self.sync_documents::<DidChangeTextDocument>(|documents, encoding| {
+            documents.change(uri, text, encoding)
+        })
+        .await
+    }
+
+    pub async fn edit(&self, uri: &Uri, edits: &[TextEdit]) -> Result<()> {
+        let edits = sort_edits(edits)?;
+
+        self.sync_documents::<DidChangeTextDocument>(|documents, encoding| {
+            documents.edit(uri, &edits, encoding)
+        })
+        .await
+    }
+
+    pub async fn close(&self, uri: &Uri) -> Result<()> {
+        self.sync_documents::<DidCloseTextDocument>(|documents, _| Ok(documents.close(uri)))
+            .await
+    }
+
+    pub async fn references(&self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
+        let references = self
+            .request::<References>(references_params(uri, symbol))
+            .await?;
+
+        Ok(references_result(uri, references))
+    }
+
+    pub async fn definitions(&self, uri: &Uri, symbol: &DocumentSymbol) -> Result<Vec<Uri>> {
+        let definitions = self
+            .request::<GotoDefinition>(definitions_params(uri, symbol))
+            .await?;
+
+        Ok(definitions_result(definitions))
+    }
+
+    pub async fn symbols(&self, uri: &Uri) -> Result<Vec<DocumentSymbol>> {
+        let symbols = self
+            .request::<DocumentSymbolRequest>(symbols_params(uri))
+            .await?;
+
+        Ok(symbols_result(symbols))
+    }
+
+    pub async fn initialize(&self, uri: Uri) -> Result<ServerCapabilities> {
+        self.initialize_with(InitializeBuilder::new(uri).build())
+            .await
+    }
+
+    pub async fn initialize_with(&self, params: InitializeParams) -> Result<ServerCapabilities> {
+        let response = self
+            .request::<Initialize>(initialize_params(params, false))
+            .await?;
+        self.set_position_encoding(PositionEncoding::negotiated(&response.capabilities));
+        self.set_document_sync(response.capabilities.text_document_sync.as_ref());
+        self.set_server_capabilities(response.capabilities.clone());
+
+        self.notify::<Initialized>(None).await?;
+
+        Ok(response.capabilities)
+    }
+}
+
+fn references_params(uri: &Uri, symbol: &DocumentSymbol) -> Option<ReferenceParams> {
+    serde_json::from_value(json!(
+        {
+            "textDocument": {
+                "uri": uri,
+            },
+            "position": symbol.selection_range.start,
+            "context": {
+                "includeDeclaration": false
             }
-            Some(DocumentSymbolResponse::Flat(flat)) => {
-                if flat.len() > 0 {
-                    panic!("Got non-empty flat documentSymbol response")
+        }
+    ))
+    .unwrap()
+}
+
+fn references_result(uri: &Uri, references: Option<Vec<Location>>) -> Vec<Uri> {
+    references
+        .unwrap_or_default()
+        .into_iter()
+        .map(|r| r.uri)
+        .filter(|r| r != uri)
+        .collect()
+}
+
+fn definitions_params(uri: &Uri, symbol: &DocumentSymbol) -> Option<GotoDefinitionParams> {
+    serde_json::from_value(json!(
+        {
+            "textDocument": {
+                "uri": uri,
+            },
+            "position": symbol.selection_range.start,
+        }
+    ))
+    .unwrap()
+}
+
+fn definitions_result(definitions: Option<GotoDefinitionResponse>) -> Vec<Uri> {
+    match definitions {
+        Some(GotoDefinitionResponse::Scalar(location)) => vec![location.uri],
+        Some(GotoDefinitionResponse::Array(vec)) => vec.into_iter().map(|l| l.uri).collect(),
+        Some(GotoDefinitionResponse::Link(vec)) => vec.into_iter().map(|l| l.target_uri).collect(),
+        None => vec![],
+    }
+}
+
+fn symbols_params(uri: &Uri) -> Option<DocumentSymbolParams> {
+    serde_json::from_value(json!(
+        {
+            "textDocument": {
+                "uri": uri
+            },
+        }
+    ))
+    .unwrap()
+}
+
+fn symbols_result(symbols: Option<DocumentSymbolResponse>) -> Vec<DocumentSymbol> {
+    match symbols {
+        Some(DocumentSymbolResponse::Nested(vec)) => {
+            let mut symbols = vec![];
+            let mut queue = vec;
+
+            // flatten nested document symbols
+            while let Some(symbol) = queue.pop() {
+                symbols.push(symbol.clone());
+                if let Some(children) = symbol.children {
+                    queue.extend(children);
                 }
+            }
 
-                vec![]
+            symbols
+        }
+        Some(DocumentSymbolResponse::Flat(flat)) => {
+            if !flat.is_empty() {
+                panic!("Got non-empty flat documentSymbol response")
             }
-            None => vec![],
+
+            vec![]
+        }
+        None => vec![],
+    }
+}
+
+fn initialize_params(
+    mut params: InitializeParams,
+    watches_files: bool,
+) -> Option<InitializeParams> {
+    // only a client watching files may take this work off the server
+    if watches_files {
+        params.capabilities = CapabilitiesBuilder::from_capabilities(params.capabilities)
+            .with_watched_files()
+            .build();
+    } else if let Some(workspace) = &mut params.capabilities.workspace {
+        // whatever params merged in from an editor claim
+        workspace.did_change_watched_files = None;
+    }
+
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::{Client, MockServer};
+
+    #[allow(deprecated)]
+    fn symbol(
+        name: &str,
+        kind: SymbolKind,
+        line: u32,
+        children: Vec<DocumentSymbol>,
+    ) -> DocumentSymbol {
+        let range = Range::new(Position::new(line, 0), Position::new(line, 10));
+
+        DocumentSymbol {
+            name: name.to_string(),
+            detail: None,
+            kind,
+            tags: None,
+            deprecated: None,
+            range,
+            selection_range: range,
+            children: Some(children),
+        }
+    }
+
+    fn location(uri: &Uri, line: u32) -> Location {
+        Location::new(
+            uri.clone(),
+            Range::new(Position::new(line, 0), Position::new(line, 1)),
+        )
+    }
+
+    #[test]
+    fn test_facade() {
+        let lib = Uri::from_str("file:///src/lib.rs").unwrap();
+        let main = Uri::from_str("file:///src/main.rs").unwrap();
+
+        let symbols = vec![symbol(
+            "Graph",
+            SymbolKind::STRUCT,
+            0,
+            vec![symbol("new", SymbolKind::METHOD, 1, vec![])],
+        )];
+
+        let (transport, handle) = MockServer::new()
+            .with_symbols(&lib, symbols)
+            .with_definitions(&lib, Position::new(1, 0), vec![location(&lib, 1)])
+            .with_references(
+                &lib,
+                Position::new(1, 0),
+                vec![location(&lib, 1), location(&main, 3), location(&main, 7)],
+            )
+            .start();
+        let client = Client::with_transport(transport);
+
+        let capabilities = client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+        assert!(capabilities.document_symbol_provider.is_some());
+
+        client.open(&lib, "struct Graph;").unwrap();
+
+        let symbols = client.symbols(&lib).unwrap();
+        let names: Vec<_> = symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["Graph", "new"]);
+
+        let new = &symbols[1];
+        assert_eq!(client.definitions(&lib, new).unwrap(), vec![lib.clone()]);
+        // references from the same file are left out
+        assert_eq!(
+            client.references(&lib, new).unwrap(),
+            vec![main.clone(), main.clone()]
+        );
+
+        // unknown to the fake workspace
+        assert!(client.symbols(&main).unwrap().is_empty());
+        assert!(client.definitions(&lib, &symbols[0]).unwrap().is_empty());
+
+        insta::assert_debug_snapshot!(handle.methods(), @r#"
+        [
+            "initialize",
+            "initialized",
+            "textDocument/didOpen",
+            "textDocument/documentSymbol",
+            "textDocument/definition",
+            "textDocument/references",
+            "textDocument/documentSymbol",
+            "textDocument/definition",
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_initialize_params() {
+        let params = InitializeParams {
+            capabilities: CapabilitiesBuilder::new().with_watched_files().build(),
+            ..Default::default()
         };
 
-        Ok(symbols)
-    }
-
-    pub fn initialize(&mut self, uri: Uri) -> Result<ServerCapabilities> {
-        let response = self.request::<Initialize>(
-            serde_json::from_value(json!(
-                {
-                    "capabilities": {
-                        "textDocument": {
-                            "documentSymbol": {
-                                "hierarchicalDocumentSymbolSupport": true,
-                            }
-                        },
-                    },
-                    "workspaceFolders": [
-                        {
-                            "uri": uri,
-                            "name": "name"
-                        }
-                    ]
-                }
-            ))
-            .unwrap(),
-        )?;
+        let watched = |params: Option<InitializeParams>| {
+            params
+                .unwrap()
+                .capabilities
+                .workspace
+                .unwrap()
+                .did_change_watched_files
+        };
+        assert!(watched(initialize_params(params.clone(), true)).is_some());
+        // nothing would answer the registrations
+        assert!(watched(initialize_params(params, false)).is_none());
+    }
 
-        self.notify::<Initialized>(None)?;
+    #[test]
+    fn test_position_encoding() {
+        let negotiate = |encoding: Option<PositionEncodingKind>| {
+            let (transport, handle) = MockServer::new()
+                .with_capabilities(ServerCapabilities {
+                    position_encoding: encoding,
+                    ..Default::default()
+                })
+                .start();
+            let client = Client::with_transport(transport);
 
-        Ok(response.capabilities)
+            assert_eq!(client.position_encoding(), PositionEncoding::Utf16);
+            client
+                .initialize(Uri::from_str("file:///").unwrap())
+                .unwrap();
+
+            let offered = handle.received()[0]["params"]["capabilities"]["general"].clone();
+            (client.position_encoding(), offered)
+        };
+
+        let (encoding, offered) = negotiate(Some(PositionEncodingKind::UTF8));
+        assert_eq!(encoding, PositionEncoding::Utf8);
+        insta::assert_snapshot!(offered, @r#"{"positionEncodings":["utf-8","utf-32","utf-16"]}"#);
+
+        let (encoding, _) = negotiate(None);
+        assert_eq!(encoding, PositionEncoding::Utf16);
+    }
+
+    #[test]
+    fn test_documents() {
+        let uri = Uri::from_str("file:///src/lib.rs").unwrap();
+        let (transport, handle) = MockServer::new().start();
+        let client = Client::with_transport(transport);
+        client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+
+        client.open(&uri, "fn a() {}\nfn b() {}\n").unwrap();
+        // open already
+        client.open(&uri, "").unwrap();
+        assert!(client.is_open(&uri));
+
+        client.change(&uri, "fn a() {}\nfn c() {}\n").unwrap();
+        client
+            .edit(
+                &uri,
+                &[
+                    TextEdit::new(
+                        Range::new(Position::new(1, 3), Position::new(1, 4)),
+                        "d".into(),
+                    ),
+                    TextEdit::new(
+                        Range::new(Position::new(0, 3), Position::new(0, 4)),
+                        "λ".into(),
+                    ),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            client.document_text(&uri).unwrap(),
+            "fn λ() {}\nfn d() {}\n"
+        );
+        assert_eq!(client.document_version(&uri), Some(3));
+
+        // rejected before anything is sent, the client still works
+        let overlapping = TextEdit::new(
+            Range::new(Position::new(0, 0), Position::new(1, 0)),
+            String::new(),
+        );
+        let err = client
+            .edit(&uri, &[overlapping.clone(), overlapping])
+            .unwrap_err();
+        insta::assert_snapshot!(err, @"edits overlap: 0:0-1:0 and 0:0-1:0");
+        assert_eq!(client.document_version(&uri), Some(3));
+
+        client.close(&uri).unwrap();
+        client.close(&uri).unwrap();
+        assert!(!client.is_open(&uri));
+        let err = client.edit(&uri, &[]).unwrap_err();
+        insta::assert_snapshot!(err, @"document not open: file:///src/lib.rs");
+
+        // wait for the server to get everything
+        client.request::<Shutdown>(None).unwrap();
+
+        let received: Vec<_> = handle
+            .received()
+            .into_iter()
+            .filter(|msg| msg["method"].as_str().unwrap().starts_with("textDocument/"))
+            .map(|msg| msg["params"].to_string())
+            .collect();
+        insta::assert_debug_snapshot!(received, @r#"
+        [
+            "{\"textDocument\":{\"languageId\":\"\",\"text\":\"fn a() {}\\nfn b() {}\\n\",\"uri\":\"file:///src/lib.rs\",\"version\":1}}",
+            "{\"contentChanges\":[{\"range\":{\"end\":{\"character\":4,\"line\":1},\"start\":{\"character\":3,\"line\":1}},\"text\":\"c\"}],\"textDocument\":{\"uri\":\"file:///src/lib.rs\",\"version\":2}}",
+            "{\"contentChanges\":[{\"range\":{\"end\":{\"character\":4,\"line\":1},\"start\":{\"character\":3,\"line\":0}},\"text\":\"λ() {}\\nfn d\"}],\"textDocument\":{\"uri\":\"file:///src/lib.rs\",\"version\":3}}",
+            "{\"textDocument\":{\"uri\":\"file:///src/lib.rs\"}}",
+        ]
+        "#);
     }
 }
@@ -0,0 +1,183 @@
+// the glob syntax of the LSP: `*` and `?` within a path segment, `**` for any
+// number of segments, `{a,b}` for alternatives and `[a-z]` or `[!a-z]` for
+// ranges of characters
+pub(crate) struct Glob {
+    // one for every combination of alternatives
+    patterns: Vec<Vec<char>>,
+}
+
+impl Glob {
+    // `None` if a `{` or `[` is never closed
+    pub(crate) fn new(pattern: &str) -> Option<Self> {
+        let patterns = expand(&pattern.chars().collect::<Vec<_>>())?;
+
+        patterns
+            .iter()
+            .all(|pattern| classes_closed(pattern))
+            .then_some(Self { patterns })
+    }
+
+    pub(crate) fn is_match(&self, path: &str) -> bool {
+        let path: Vec<_> = path.chars().collect();
+
+        self.patterns.iter().any(|pattern| matches(pattern, &path))
+    }
+}
+
+fn expand(pattern: &[char]) -> Option<Vec<Vec<char>>> {
+    let Some(open) = pattern.iter().position(|&c| c == '{') else {
+        return Some(vec![pattern.to_vec()]);
+    };
+
+    let mut depth = 0;
+    let mut alternatives = vec![];
+    let mut start = open + 1;
+    for (i, &c) in pattern.iter().enumerate().skip(open) {
+        match c {
+            '{' => depth += 1,
+            ',' if depth == 1 => {
+                alternatives.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            '}' if depth == 1 => {
+                alternatives.push(&pattern[start..i]);
+
+                let (prefix, suffix) = (&pattern[..open], &pattern[i + 1..]);
+                let mut patterns = vec![];
+                for alternative in alternatives {
+                    // alternatives may be nested, and there may be more later on
+                    patterns.extend(expand(&[prefix, alternative, suffix].concat())?);
+                }
+
+                return Some(patterns);
+            }
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn classes_closed(pattern: &[char]) -> bool {
+    let mut rest = pattern;
+    while let Some(open) = rest.iter().position(|&c| c == '[') {
+        match class_end(&rest[open + 1..]) {
+            Some(end) => rest = &rest[open + 1 + end + 1..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+// the index of the `]` closing a class, a `]` right at the start is part of it
+fn class_end(class: &[char]) -> Option<usize> {
+    let skip = match class {
+        ['!', ']', ..] => 2,
+        ['!', ..] | [']', ..] => 1,
+        _ => 0,
+    };
+
+    class
+        .iter()
+        .skip(skip)
+        .position(|&c| c == ']')
+        .map(|i| i + skip)
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*'] => true,
+        ['*', '*', '/', rest @ ..] => {
+            matches(rest, path)
+                || (0..path.len()).any(|i| path[i] == '/' && matches(rest, &path[i + 1..]))
+        }
+        ['*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=segment).any(|i| matches(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => {
+            matches!(path.first(), Some(&c) if c != '/') && matches(rest, &path[1..])
+        }
+        ['[', class @ ..] => {
+            let Some(end) = class_end(class) else {
+                return false;
+            };
+
+            matches!(path.first(), Some(&c) if c != '/' && class_matches(&class[..end], c))
+                && matches(&class[end + 1..], &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+    }
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, mut class) = match class {
+        ['!', class @ ..] => (true, class),
+        class => (false, class),
+    };
+
+    let mut found = false;
+    while !class.is_empty() {
+        match class {
+            [from, '-', to, rest @ ..] => {
+                found |= (*from..=*to).contains(&c);
+                class = rest;
+            }
+            [other, rest @ ..] => {
+                found |= *other == c;
+                class = rest;
+            }
+            [] => unreachable!(),
+        }
+    }
+
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/main.rs", false),
+            ("**/*.rs", "main.rs", true),
+            ("**/*.rs", "src/bin/main.rs", true),
+            ("**/*.rs", "src/main.toml", false),
+            ("/work/**", "/work/src/main.rs", true),
+            ("src/**/mod.rs", "src/mod.rs", true),
+            ("src/**/mod.rs", "src/a/b/mod.rs", true),
+            ("src/**/mod.rs", "src/a/mod.rs.bak", false),
+            ("**/*.{rs,toml}", "Cargo.toml", true),
+            ("**/*.{rs,toml}", "README.md", false),
+            ("{src,{tests,benches}}/*.rs", "benches/a.rs", true),
+            ("{a,b}/{c,d}", "b/c", true),
+            ("?.rs", "a.rs", true),
+            ("?.rs", "ab.rs", false),
+            ("?", "/", false),
+            ("[a-c].rs", "b.rs", true),
+            ("[a-c].rs", "d.rs", false),
+            ("[!a-c].rs", "d.rs", true),
+            ("[]]", "]", true),
+            ("λ*", "λé", true),
+        ];
+
+        for (pattern, path, expected) in cases {
+            let glob = Glob::new(pattern).unwrap();
+            assert_eq!(glob.is_match(path), expected, "{} on {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Glob::new("{a,b").is_none());
+        assert!(Glob::new("[a-").is_none());
+        assert!(Glob::new("{a,[b}").is_none());
+        assert!(Glob::new("a}").is_some());
+    }
+}
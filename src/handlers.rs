@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use lsp_types::request::*;
+use lsp_types::ConfigurationParams;
+use serde_json::{json, Value};
+
+use crate::jsonrpc::{self, ErrorCode};
+
+type Handler = Arc<dyn Fn(Option<Value>) -> Result<Value, jsonrpc::Error> + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Handlers {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl Handlers {
+    pub(crate) fn insert<R: Request>(
+        &mut self,
+        handler: impl Fn(R::Params) -> Result<R::Result, jsonrpc::Error> + Send + Sync + 'static,
+    ) {
+        let handler = move |params: Option<Value>| {
+            let params =
+                serde_json::from_value(params.unwrap_or_default()).map_err(invalid_params)?;
+
+            serde_json::to_value(handler(params)?).map_err(|err| {
+                jsonrpc::Error::new(ErrorCode::InternalError, format!("invalid result: {}", err))
+            })
+        };
+
+        self.handlers.insert(R::METHOD, Arc::new(handler));
+    }
+
+    pub(crate) fn get(&self, method: &str) -> Option<Handler> {
+        self.handlers.get(method).cloned()
+    }
+}
+
+pub(crate) fn handle(
+    handler: Option<Handler>,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, jsonrpc::Error> {
+    if let Some(handler) = handler {
+        return handler(params);
+    }
+
+    match method {
+        // no settings, for every item asked for
+        WorkspaceConfiguration::METHOD => {
+            let params: ConfigurationParams =
+                serde_json::from_value(params.unwrap_or_default()).map_err(invalid_params)?;
+
+            Ok(Value::Array(vec![Value::Null; params.items.len()]))
+        }
+        // edits are never applied, the files are only read
+        ApplyWorkspaceEdit::METHOD => Ok(json!({
+            "applied": false,
+            "failureReason": "editing is not supported",
+        })),
+        ShowDocument::METHOD => Ok(json!({ "success": false })),
+        RegisterCapability::METHOD
+        | UnregisterCapability::METHOD
+        | WorkDoneProgressCreate::METHOD
+        | ShowMessageRequest::METHOD
+        | WorkspaceFoldersRequest::METHOD
+        | CodeLensRefresh::METHOD
+        | SemanticTokensRefresh::METHOD
+        | InlayHintRefreshRequest::METHOD
+        | InlineValueRefreshRequest::METHOD
+        | WorkspaceDiagnosticRefresh::METHOD => Ok(Value::Null),
+        _ => Err(jsonrpc::Error::new(
+            ErrorCode::MethodNotFound,
+            format!("unhandled method {:?}", method),
+        )),
+    }
+}
+
+fn invalid_params(err: serde_json::Error) -> jsonrpc::Error {
+    jsonrpc::Error::new(ErrorCode::InvalidParams, format!("invalid params: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_all(handlers: &Handlers, requests: &[(&str, Value)]) -> Vec<String> {
+        requests
+            .iter()
+            .map(|(method, params)| {
+                match handle(handlers.get(method), method, Some(params.clone())) {
+                    Ok(result) => result.to_string(),
+                    Err(err) => err.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_handle() {
+        let mut handlers = Handlers::default();
+        handlers.insert::<ShowDocument>(|params| {
+            Ok(lsp_types::ShowDocumentResult {
+                success: params.external == Some(true),
+            })
+        });
+
+        let results = handle_all(
+            &handlers,
+            &[
+                (
+                    "workspace/configuration",
+                    json!({"items": [{"section": "a"}, {}]}),
+                ),
+                ("workspace/configuration", json!({})),
+                ("window/workDoneProgress/create", json!({"token": 1})),
+                ("workspace/applyEdit", json!({"edit": {}})),
+                (
+                    "window/showDocument",
+                    json!({"uri": "file:///a", "external": true}),
+                ),
+                ("window/showDocument", json!({})),
+                ("x/unknown", Value::Null),
+            ],
+        );
+
+        insta::assert_debug_snapshot!(results, @r#"
+        [
+            "[null,null]",
+            "Error -32602: invalid params: missing field `items`",
+            "null",
+            "{\"applied\":false,\"failureReason\":\"editing is not supported\"}",
+            "{\"success\":true}",
+            "Error -32602: invalid params: missing field `uri`",
+            "Error -32601: unhandled method \"x/unknown\"",
+        ]
+        "#);
+    }
+}
@@ -0,0 +1,312 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use lsp_types::*;
+use serde_json::{json, Value};
+
+use crate::line_index::PositionEncoding;
+
+#[derive(Debug, Clone)]
+pub struct InitializeBuilder {
+    params: InitializeParams,
+}
+
+impl InitializeBuilder {
+    pub fn new(root: Uri) -> Self {
+        Self {
+            params: InitializeParams {
+                capabilities: CapabilitiesBuilder::facade().build(),
+                ..Default::default()
+            },
+        }
+        .with_root(root)
+    }
+
+    // fields unknown to `lsp-types` are dropped
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let params =
+            serde_json::from_value(read(path.as_ref())?).context("parsing initialize params")?;
+
+        Ok(Self { params })
+    }
+
+    // objects are merged recursively, everything else is replaced
+    pub fn merge(self, params: Value) -> Result<Self> {
+        let mut merged = serde_json::to_value(self.params)?;
+        merge(&mut merged, params);
+
+        Ok(Self {
+            params: serde_json::from_value(merged).context("parsing initialize params")?,
+        })
+    }
+
+    pub fn merge_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        self.merge(read(path.as_ref())?)
+    }
+
+    // replaces the root of params loaded from elsewhere too
+    #[allow(deprecated)]
+    pub fn with_root(mut self, root: Uri) -> Self {
+        self.params.root_uri = Some(root.clone());
+        self.params.root_path = None;
+
+        let name = folder_name(&root);
+        self.params.workspace_folders = Some(vec![WorkspaceFolder { uri: root, name }]);
+        self
+    }
+
+    pub fn with_workspace_folder(mut self, uri: Uri, name: impl Into<String>) -> Self {
+        let folders = self.params.workspace_folders.get_or_insert_with(Vec::new);
+        folders.push(WorkspaceFolder {
+            uri,
+            name: name.into(),
+        });
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: ClientCapabilities) -> Self {
+        self.params.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_client_info(mut self, name: impl Into<String>, version: Option<&str>) -> Self {
+        self.params.client_info = Some(ClientInfo {
+            name: name.into(),
+            version: version.map(str::to_string),
+        });
+        self
+    }
+
+    pub fn with_process_id(mut self, process_id: Option<u32>) -> Self {
+        self.params.process_id = process_id;
+        self
+    }
+
+    pub fn with_initialization_options(mut self, options: Value) -> Self {
+        self.params.initialization_options = Some(options);
+        self
+    }
+
+    pub fn build(self) -> InitializeParams {
+        self.params
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CapabilitiesBuilder {
+    capabilities: ClientCapabilities,
+}
+
+impl CapabilitiesBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // what the facade and `Readiness` need
+    pub fn facade() -> Self {
+        Self::new()
+            .with_position_encodings(&PositionEncoding::ALL)
+            .with_hierarchical_symbols()
+            .with_work_done_progress()
+            .with_server_status()
+    }
+
+    pub fn from_capabilities(capabilities: ClientCapabilities) -> Self {
+        Self { capabilities }
+    }
+
+    // from most to least preferred
+    pub fn with_position_encodings(mut self, encodings: &[PositionEncoding]) -> Self {
+        let general = self
+            .capabilities
+            .general
+            .get_or_insert_with(Default::default);
+        general.position_encodings = Some(encodings.iter().map(|e| e.kind()).collect());
+        self
+    }
+
+    pub fn with_hierarchical_symbols(mut self) -> Self {
+        let text_document = self
+            .capabilities
+            .text_document
+            .get_or_insert_with(Default::default);
+        let document_symbol = text_document
+            .document_symbol
+            .get_or_insert_with(Default::default);
+        document_symbol.hierarchical_document_symbol_support = Some(true);
+        self
+    }
+
+    pub fn with_work_done_progress(mut self) -> Self {
+        let window = self
+            .capabilities
+            .window
+            .get_or_insert_with(Default::default);
+        window.work_done_progress = Some(true);
+        self
+    }
+
+    // rust-analyzer's `experimental/serverStatus`
+    pub fn with_server_status(self) -> Self {
+        self.with_experimental("serverStatusNotification", json!(true))
+    }
+
+    pub fn with_watched_files(mut self) -> Self {
+        let workspace = self
+            .capabilities
+            .workspace
+            .get_or_insert_with(Default::default);
+        workspace.did_change_watched_files = Some(DidChangeWatchedFilesClientCapabilities {
+            dynamic_registration: Some(true),
+            relative_pattern_support: Some(true),
+        });
+        self
+    }
+
+    pub fn with_experimental(mut self, key: &str, value: Value) -> Self {
+        let experimental = self
+            .capabilities
+            .experimental
+            .get_or_insert_with(|| json!({}));
+        merge(experimental, json!({ key: value }));
+        self
+    }
+
+    pub fn build(self) -> ClientCapabilities {
+        self.capabilities
+    }
+}
+
+fn read(path: &Path) -> Result<Value> {
+    let file =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    serde_json::from_str(&file).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn merge(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Object(into), Value::Object(from)) => {
+            for (key, value) in from {
+                merge(into.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (into, from) => *into = from,
+    }
+}
+
+fn folder_name(uri: &Uri) -> String {
+    let segment = uri
+        .path()
+        .segments()
+        .rev()
+        .find(|segment| !segment.as_str().is_empty());
+
+    match segment {
+        Some(segment) => segment.decode().into_string_lossy().into_owned(),
+        None => uri.as_str().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let root = Uri::from_str("file:///work/my%20crate/").unwrap();
+        let params = InitializeBuilder::new(root).build();
+
+        insta::assert_snapshot!(serde_json::to_string_pretty(&params).unwrap(), @r#"
+        {
+          "processId": null,
+          "rootUri": "file:///work/my%20crate/",
+          "capabilities": {
+            "textDocument": {
+              "documentSymbol": {
+                "hierarchicalDocumentSymbolSupport": true
+              }
+            },
+            "window": {
+              "workDoneProgress": true
+            },
+            "general": {
+              "positionEncodings": [
+                "utf-8",
+                "utf-32",
+                "utf-16"
+              ]
+            },
+            "experimental": {
+              "serverStatusNotification": true
+            }
+          },
+          "workspaceFolders": [
+            {
+              "uri": "file:///work/my%20crate/",
+              "name": "my crate"
+            }
+          ]
+        }
+        "#);
+    }
+
+    #[test]
+    fn test_merge() {
+        let config = concat!(env!("CARGO_MANIFEST_DIR"), "/config.json");
+        let root = Uri::from_str("file:///work").unwrap();
+
+        let params = InitializeBuilder::new(root.clone())
+            .with_client_info("code-graph", Some("1.0"))
+            .merge_file(config)
+            .unwrap()
+            .with_root(root)
+            .with_process_id(Some(7))
+            .with_initialization_options(json!({"cargo": {"features": "all"}}))
+            .build();
+
+        // the editor's, but in this workspace
+        assert_eq!(params.process_id, Some(7));
+        assert_eq!(params.client_info.unwrap().name, "Visual Studio Code");
+        #[allow(deprecated)]
+        let root_uri = params.root_uri.unwrap();
+        assert_eq!(root_uri.as_str(), "file:///work");
+        assert_eq!(
+            serde_json::to_value(&params.workspace_folders).unwrap(),
+            json!([{"uri": "file:///work", "name": "work"}])
+        );
+
+        // objects are merged, everything else replaced
+        let capabilities = params.capabilities;
+        assert_eq!(
+            capabilities.general.unwrap().position_encodings,
+            Some(vec![PositionEncodingKind::UTF16])
+        );
+        assert!(capabilities
+            .workspace
+            .unwrap()
+            .did_change_watched_files
+            .is_some());
+        assert_eq!(
+            capabilities.experimental.unwrap()["serverStatusNotification"],
+            json!(true)
+        );
+        assert_eq!(
+            params.initialization_options,
+            Some(json!({"cargo": {"features": "all"}}))
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = CapabilitiesBuilder::new()
+            .with_watched_files()
+            .with_experimental("a", json!(1))
+            .with_experimental("b", json!(2))
+            .build();
+
+        insta::assert_snapshot!(serde_json::to_string(&capabilities).unwrap(), @r#"{"workspace":{"didChangeWatchedFiles":{"dynamicRegistration":true,"relativePatternSupport":true}},"experimental":{"a":1,"b":2}}"#);
+    }
+}
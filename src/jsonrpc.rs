@@ -1,13 +1,52 @@
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(untagged)]
+pub enum Id {
+    Number(i64),
+    String(String),
+    #[default]
+    Null,
+}
+
+impl From<i64> for Id {
+    fn from(value: i64) -> Self {
+        Id::Number(value)
+    }
+}
+
+impl From<String> for Id {
+    fn from(value: String) -> Self {
+        Id::String(value)
+    }
+}
+
+impl From<&str> for Id {
+    fn from(value: &str) -> Self {
+        Id::String(value.to_string())
+    }
+}
+
+impl std::fmt::Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Id::Number(id) => write!(f, "{}", id),
+            Id::String(id) => write!(f, "{:?}", id),
+            Id::Null => write!(f, "null"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request<Params> {
     pub jsonrpc: String,
     pub method: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub params: Option<Params>,
-    pub id: i64,
+    pub id: Id,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,15 +63,213 @@ pub struct Response<T: Serialize + DeserializeOwned> {
     #[serde(flatten)]
     #[serde(with = "JsonRpcResult")]
     pub result: Result<T, Error>,
-    pub id: Option<i64>,
+    #[serde(default)]
+    pub id: Id,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(transparent)]
+pub struct Batch<T>(pub Vec<T>);
+
+impl<T: Serialize + DeserializeOwned> Batch<Response<T>> {
+    pub fn into_results(self) -> HashMap<Id, Result<T, Error>> {
+        self.0
+            .into_iter()
+            .map(|response| (response.id, response.result))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Message {
+    Request(Request<Value>),
+    Notification(Notification<Value>),
+    Response(Response<Value>),
+}
+
+impl Message {
+    pub fn from_value(value: Value) -> Result<Self, MalformedMessage> {
+        let Value::Object(object) = &value else {
+            return Err(MalformedMessage::NotAnObject(value));
+        };
+
+        match object.get("jsonrpc") {
+            Some(Value::String(version)) if version == "2.0" => {}
+            version => return Err(MalformedMessage::InvalidVersion(version.cloned())),
+        }
+
+        let has_method = object.contains_key("method");
+        let has_id = object.contains_key("id");
+        let has_result = object.contains_key("result");
+        let has_error = object.contains_key("error");
+
+        let message = match (has_method, has_id, has_result, has_error) {
+            (true, true, false, false) => serde_json::from_value(value).map(Message::Request),
+            (true, false, false, false) => serde_json::from_value(value).map(Message::Notification),
+            (false, _, true, false) | (false, _, false, true) => {
+                serde_json::from_value(value).map(Message::Response)
+            }
+            (false, _, true, true) => return Err(MalformedMessage::ResultAndError),
+            _ => return Err(MalformedMessage::Unknown),
+        };
+
+        message.map_err(MalformedMessage::InvalidField)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MalformedMessage> {
+        let value = serde_json::from_slice(bytes).map_err(MalformedMessage::InvalidJson)?;
+
+        Self::from_value(value)
+    }
+
+    pub fn id(&self) -> Option<&Id> {
+        match self {
+            Message::Request(request) => Some(&request.id),
+            Message::Notification(_) => None,
+            Message::Response(response) => Some(&response.id),
+        }
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Message::Request(request) => Some(&request.method),
+            Message::Notification(notification) => Some(&notification.method),
+            Message::Response(_) => None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        Message::from_value(value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+pub enum MalformedMessage {
+    InvalidJson(serde_json::Error),
+    NotAnObject(Value),
+    InvalidVersion(Option<Value>),
+    ResultAndError,
+    Unknown,
+    InvalidField(serde_json::Error),
+}
+
+impl std::fmt::Display for MalformedMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MalformedMessage::InvalidJson(err) => write!(f, "invalid json: {}", err),
+            MalformedMessage::NotAnObject(value) => {
+                write!(f, "message is not an object: {}", value)
+            }
+            MalformedMessage::InvalidVersion(Some(version)) => {
+                write!(f, "unsupported jsonrpc version: {}", version)
+            }
+            MalformedMessage::InvalidVersion(None) => write!(f, "missing jsonrpc version"),
+            MalformedMessage::ResultAndError => {
+                write!(f, "response has both 'result' and 'error'")
+            }
+            MalformedMessage::Unknown => {
+                write!(f, "message is not a request, notification or response")
+            }
+            MalformedMessage::InvalidField(err) => write!(f, "invalid message field: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MalformedMessage {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MalformedMessage::InvalidJson(err) | MalformedMessage::InvalidField(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Error {
-    code: i64,
-    message: String,
+    pub code: ErrorCode,
+    pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<Value>,
+    pub data: Option<Value>,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: impl Serialize) -> serde_json::Result<Self> {
+        self.data = Some(serde_json::to_value(data)?);
+        Ok(self)
+    }
+
+    pub fn data<D: DeserializeOwned>(&self) -> serde_json::Result<Option<D>> {
+        self.data.clone().map(serde_json::from_value).transpose()
+    }
+}
+
+// unknown codes are kept as `Other`, so they survive a round trip
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "i64", into = "i64")]
+pub enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    ServerNotInitialized,
+    UnknownErrorCode,
+    RequestFailed,
+    ServerCancelled,
+    ContentModified,
+    RequestCancelled,
+    Other(i64),
+}
+
+impl From<i64> for ErrorCode {
+    fn from(value: i64) -> Self {
+        match value {
+            -32700 => ErrorCode::ParseError,
+            -32600 => ErrorCode::InvalidRequest,
+            -32601 => ErrorCode::MethodNotFound,
+            -32602 => ErrorCode::InvalidParams,
+            -32603 => ErrorCode::InternalError,
+            -32002 => ErrorCode::ServerNotInitialized,
+            -32001 => ErrorCode::UnknownErrorCode,
+            -32803 => ErrorCode::RequestFailed,
+            -32802 => ErrorCode::ServerCancelled,
+            -32801 => ErrorCode::ContentModified,
+            -32800 => ErrorCode::RequestCancelled,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for i64 {
+    fn from(value: ErrorCode) -> Self {
+        match value {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::ServerNotInitialized => -32002,
+            ErrorCode::UnknownErrorCode => -32001,
+            ErrorCode::RequestFailed => -32803,
+            ErrorCode::ServerCancelled => -32802,
+            ErrorCode::ContentModified => -32801,
+            ErrorCode::RequestCancelled => -32800,
+            ErrorCode::Other(code) => code,
+        }
+    }
 }
 
 type Remote<T> = Result<T, Error>;
@@ -48,7 +285,7 @@ enum JsonRpcResult<T> {
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error {}: {}", self.code, self.message)
+        write!(f, "Error {}: {}", i64::from(self.code), self.message)
     }
 }
 
@@ -67,7 +304,7 @@ mod tests {
                 jsonrpc: "2.0".to_string(),
                 method: "subtract".to_string(),
                 params: Some(vec![42, 23]),
-                id: 1,
+                id: Id::Number(1),
             },
             @r#"{"jsonrpc": "2.0", "method": "subtract", "params": [42, 23], "id": 1}"#
         );
@@ -80,7 +317,7 @@ mod tests {
                 jsonrpc: "2.0".to_string(),
                 method: "method".to_string(),
                 params: Some(()),
-                id: 1,
+                id: Id::Number(1),
             },
             @r###"{"jsonrpc": "2.0", "method": "method", "params": null, "id": 1}"###
         );
@@ -90,7 +327,7 @@ mod tests {
                 jsonrpc: "2.0".to_string(),
                 method: "method".to_string(),
                 params: None,
-                id: 1,
+                id: Id::Number(1),
             },
             @r#"{"jsonrpc": "2.0", "method": "method", "id": 1}"#
         );
@@ -111,7 +348,9 @@ mod tests {
                         23,
                     ],
                 ),
-                id: 1,
+                id: Number(
+                    1,
+                ),
             },
         )
         "###
@@ -157,7 +396,7 @@ mod tests {
             Response {
                 jsonrpc: "2.0".to_string(),
                 result: Ok(19),
-                id: Some(1),
+                id: Id::Number(1),
             },
             @r###"{"jsonrpc": "2.0", "result": 19, "id": 1}"###
         );
@@ -166,11 +405,11 @@ mod tests {
             Response::<()> {
                 jsonrpc: "2.0".to_string(),
                 result: Err(Error {
-                    code: -32601,
+                    code: ErrorCode::MethodNotFound,
                     message: "Method not found".to_string(),
                     data: Some(json!(["Some", "data"]))
                 }),
-                id: None,
+                id: Id::Null,
             },
             @r###"{"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found", "data": ["Some", "data"]}, "id": null}"###
         );
@@ -187,7 +426,7 @@ mod tests {
                 result: Ok(
                     19,
                 ),
-                id: Some(
+                id: Number(
                     1,
                 ),
             },
@@ -203,7 +442,7 @@ mod tests {
                 jsonrpc: "2.0",
                 result: Err(
                     Error {
-                        code: -32601,
+                        code: MethodNotFound,
                         message: "Method not found",
                         data: Some(
                             Array [
@@ -213,10 +452,314 @@ mod tests {
                         ),
                     },
                 ),
-                id: None,
+                id: Null,
+            },
+        )
+        "#
+        );
+    }
+
+    #[test]
+    fn test_id_serialization() {
+        insta::assert_compact_json_snapshot!(
+            Request::<Option<()>> {
+                jsonrpc: "2.0".to_string(),
+                method: "method".to_string(),
+                params: None,
+                id: Id::String("abc".to_string()),
+            },
+            @r#"{"jsonrpc": "2.0", "method": "method", "id": "abc"}"#
+        );
+
+        insta::assert_compact_json_snapshot!(
+            Response {
+                jsonrpc: "2.0".to_string(),
+                result: Ok(19),
+                id: Id::String("abc".to_string()),
+            },
+            @r#"{"jsonrpc": "2.0", "result": 19, "id": "abc"}"#
+        );
+
+        insta::assert_compact_json_snapshot!(
+            Response {
+                jsonrpc: "2.0".to_string(),
+                result: Ok(19),
+                id: Id::Null,
+            },
+            @r#"{"jsonrpc": "2.0", "result": 19, "id": null}"#
+        );
+    }
+
+    #[test]
+    fn test_id_deserialization() {
+        insta::assert_debug_snapshot!(
+            serde_json::from_str::<Request<()>>(r#"{"jsonrpc": "2.0", "method": "method", "id": "abc"}"#),
+            @r#"
+        Ok(
+            Request {
+                jsonrpc: "2.0",
+                method: "method",
+                params: None,
+                id: String(
+                    "abc",
+                ),
+            },
+        )
+        "#
+        );
+
+        insta::assert_debug_snapshot!(
+            serde_json::from_str::<Response<i32>>(r#"{"jsonrpc": "2.0", "result": 19, "id": "abc"}"#),
+            @r#"
+        Ok(
+            Response {
+                jsonrpc: "2.0",
+                result: Ok(
+                    19,
+                ),
+                id: String(
+                    "abc",
+                ),
+            },
+        )
+        "#
+        );
+
+        insta::assert_debug_snapshot!(
+            serde_json::from_str::<Response<i32>>(r#"{"jsonrpc": "2.0", "result": 19}"#),
+            @r#"
+        Ok(
+            Response {
+                jsonrpc: "2.0",
+                result: Ok(
+                    19,
+                ),
+                id: Null,
             },
         )
         "#
         );
     }
+
+    #[test]
+    fn test_batch_serialization() {
+        insta::assert_compact_json_snapshot!(
+            Batch(vec![
+                Request::<()> {
+                    jsonrpc: "2.0".to_string(),
+                    method: "a".to_string(),
+                    params: None,
+                    id: Id::Number(1),
+                },
+                Request::<()> {
+                    jsonrpc: "2.0".to_string(),
+                    method: "b".to_string(),
+                    params: None,
+                    id: Id::String("2".to_string()),
+                },
+            ]),
+            @r#"[{"jsonrpc": "2.0", "method": "a", "id": 1}, {"jsonrpc": "2.0", "method": "b", "id": "2"}]"#
+        );
+    }
+
+    #[test]
+    fn test_batch_deserialization() {
+        let batch = serde_json::from_str::<Batch<Response<i32>>>(
+            r#"[
+                {"jsonrpc": "2.0", "result": 19, "id": 2},
+                {"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": "1"},
+                {"jsonrpc": "2.0", "result": 7, "id": 1}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut results: Vec<_> = batch.into_results().into_iter().collect();
+        results.sort_by_key(|(id, _)| id.to_string());
+
+        insta::assert_debug_snapshot!(results, @r#"
+        [
+            (
+                String(
+                    "1",
+                ),
+                Err(
+                    Error {
+                        code: MethodNotFound,
+                        message: "Method not found",
+                        data: None,
+                    },
+                ),
+            ),
+            (
+                Number(
+                    1,
+                ),
+                Ok(
+                    7,
+                ),
+            ),
+            (
+                Number(
+                    2,
+                ),
+                Ok(
+                    19,
+                ),
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_error_code_round_trip() {
+        insta::assert_compact_json_snapshot!(
+            [
+                ErrorCode::ParseError,
+                ErrorCode::ContentModified,
+                ErrorCode::RequestCancelled,
+                ErrorCode::Other(-1),
+            ],
+            @"[-32700, -32801, -32800, -1]"
+        );
+
+        insta::assert_debug_snapshot!(
+            serde_json::from_str::<Vec<ErrorCode>>("[-32002, -32802, -32803, 7]"),
+            @r"
+        Ok(
+            [
+                ServerNotInitialized,
+                ServerCancelled,
+                RequestFailed,
+                Other(
+                    7,
+                ),
+            ],
+        )
+        "
+        );
+    }
+
+    #[test]
+    fn test_error_data() {
+        let error: Error = serde_json::from_str(
+            r#"{"code": -32603, "message": "Internal error", "data": {"retry": true}}"#,
+        )
+        .unwrap();
+
+        #[derive(Deserialize, Debug)]
+        struct Data {
+            retry: bool,
+        }
+
+        assert!(error.data::<Data>().unwrap().unwrap().retry);
+        assert!(error.data::<Vec<i32>>().is_err());
+        assert!(Error::new(ErrorCode::InternalError, "")
+            .data::<Data>()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_message_deserialization() {
+        insta::assert_debug_snapshot!(
+            serde_json::from_str::<Vec<Message>>(r#"[
+                {"jsonrpc": "2.0", "method": "workspace/configuration", "params": {"items": []}, "id": "a"},
+                {"jsonrpc": "2.0", "method": "initialized"},
+                {"jsonrpc": "2.0", "result": null, "id": 1},
+                {"jsonrpc": "2.0", "error": {"code": -32800, "message": "Request cancelled"}, "id": 2}
+            ]"#),
+            @r#"
+        Ok(
+            [
+                Request(
+                    Request {
+                        jsonrpc: "2.0",
+                        method: "workspace/configuration",
+                        params: Some(
+                            Object {
+                                "items": Array [],
+                            },
+                        ),
+                        id: String(
+                            "a",
+                        ),
+                    },
+                ),
+                Notification(
+                    Notification {
+                        jsonrpc: "2.0",
+                        method: "initialized",
+                        params: None,
+                    },
+                ),
+                Response(
+                    Response {
+                        jsonrpc: "2.0",
+                        result: Ok(
+                            Null,
+                        ),
+                        id: Number(
+                            1,
+                        ),
+                    },
+                ),
+                Response(
+                    Response {
+                        jsonrpc: "2.0",
+                        result: Err(
+                            Error {
+                                code: RequestCancelled,
+                                message: "Request cancelled",
+                                data: None,
+                            },
+                        ),
+                        id: Number(
+                            2,
+                        ),
+                    },
+                ),
+            ],
+        )
+        "#
+        );
+    }
+
+    #[test]
+    fn test_message_serialization() {
+        insta::assert_compact_json_snapshot!(
+            Message::from_slice(br#"{"jsonrpc": "2.0", "method": "exit"}"#).unwrap(),
+            @r#"{"jsonrpc": "2.0", "method": "exit"}"#
+        );
+
+        insta::assert_compact_json_snapshot!(
+            Message::from_slice(br#"{"jsonrpc": "2.0", "result": [1], "id": 3}"#).unwrap(),
+            @r#"{"jsonrpc": "2.0", "result": [1], "id": 3}"#
+        );
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let errors = [
+            &b"{"[..],
+            br#"[]"#,
+            br#"{"method": "exit"}"#,
+            br#"{"jsonrpc": "1.0", "method": "exit"}"#,
+            br#"{"jsonrpc": "2.0", "result": 1, "error": {"code": 1, "message": ""}, "id": 1}"#,
+            br#"{"jsonrpc": "2.0", "id": 1}"#,
+            br#"{"jsonrpc": "2.0", "method": 1}"#,
+        ]
+        .map(|msg| Message::from_slice(msg).unwrap_err().to_string());
+
+        insta::assert_debug_snapshot!(errors, @r#"
+        [
+            "invalid json: EOF while parsing an object at line 1 column 1",
+            "message is not an object: []",
+            "missing jsonrpc version",
+            "unsupported jsonrpc version: \"1.0\"",
+            "response has both 'result' and 'error'",
+            "message is not a request, notification or response",
+            "invalid message field: invalid type: integer `1`, expected a string",
+        ]
+        "#);
+    }
 }
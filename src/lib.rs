@@ -1,5 +1,41 @@
+#[cfg(feature = "async")]
+mod async_client;
+mod cancel;
+mod capabilities;
 mod client;
+pub mod codec;
+mod documents;
 mod facade;
-mod jsonrpc;
+mod glob;
+mod handlers;
+mod initialize;
+pub mod jsonrpc;
+mod line_index;
+mod mock;
+mod notifications;
+pub mod protocol;
+mod readiness;
+mod replay;
+mod supervisor;
+#[cfg(test)]
+mod test_util;
+mod trace;
+pub mod transport;
+#[cfg(feature = "watch")]
+mod watcher;
 
-pub use client::Client;
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+pub use cancel::CancellationToken;
+pub use capabilities::Capabilities;
+pub use client::{Client, RequestTimeout, Stall};
+pub use initialize::{CapabilitiesBuilder, InitializeBuilder};
+pub use line_index::{LineIndex, PositionEncoding};
+pub use mock::{MockHandle, MockServer};
+pub use readiness::{Readiness, ServerStatus, ServerStatusParams};
+pub use replay::Replay;
+pub use supervisor::{Restart, Supervisor};
+pub use trace::TraceLog;
+pub use transport::Transport;
+#[cfg(feature = "watch")]
+pub use watcher::Watcher;
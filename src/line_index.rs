@@ -0,0 +1,259 @@
+use lsp_types::{Position, PositionEncodingKind, ServerCapabilities};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PositionEncoding {
+    Utf8,
+    // the default when the server doesn't pick one
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    // from most to least preferred
+    pub const ALL: [Self; 3] = [Self::Utf8, Self::Utf32, Self::Utf16];
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    pub fn from_kind(kind: &PositionEncodingKind) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|encoding| encoding.kind() == *kind)
+    }
+
+    // UTF-16 if the server didn't pick one, or picked one it wasn't offered
+    pub fn negotiated(capabilities: &ServerCapabilities) -> Self {
+        capabilities
+            .position_encoding
+            .as_ref()
+            .and_then(Self::from_kind)
+            .unwrap_or_default()
+    }
+
+    fn len(self, c: char) -> u32 {
+        match self {
+            Self::Utf8 => c.len_utf8() as u32,
+            Self::Utf16 => c.len_utf16() as u32,
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+// lines end with `\n`, `\r\n` or `\r`. positions past the end of a line are
+// clamped to it, and positions inside of a character to its start
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: impl Into<String>) -> Self {
+        let text = text.into();
+
+        let mut line_starts = vec![0];
+        let bytes = text.as_bytes();
+        for (i, &b) in bytes.iter().enumerate() {
+            match b {
+                b'\n' => line_starts.push(i + 1),
+                // a `\r\n` ends the line at the `\n`
+                b'\r' if bytes.get(i + 1) != Some(&b'\n') => line_starts.push(i + 1),
+                _ => {}
+            }
+        }
+
+        Self { text, line_starts }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    pub fn offset(&self, position: Position, encoding: PositionEncoding) -> usize {
+        let Some(&start) = self.line_starts.get(position.line as usize) else {
+            return self.text.len();
+        };
+
+        let mut units = 0;
+        for (i, c) in self.line(position.line as usize).char_indices() {
+            units += encoding.len(c);
+            if units > position.character {
+                return start + i;
+            }
+        }
+
+        start + self.line(position.line as usize).len()
+    }
+
+    pub fn position(&self, offset: usize, encoding: PositionEncoding) -> Position {
+        let offset = self.floor_char_boundary(offset);
+
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+
+        // inside of the line ending
+        let end = (offset - start).min(self.line(line).len());
+        let character = self.line(line)[..end]
+            .chars()
+            .map(|c| encoding.len(c))
+            .sum();
+
+        Position::new(line as u32, character)
+    }
+
+    pub fn char_offset(&self, position: Position, encoding: PositionEncoding) -> usize {
+        self.byte_to_char(self.offset(position, encoding))
+    }
+
+    pub fn char_position(&self, offset: usize, encoding: PositionEncoding) -> Position {
+        self.position(self.char_to_byte(offset), encoding)
+    }
+
+    pub fn convert(
+        &self,
+        position: Position,
+        from: PositionEncoding,
+        to: PositionEncoding,
+    ) -> Position {
+        self.position(self.offset(position, from), to)
+    }
+
+    pub fn byte_to_char(&self, offset: usize) -> usize {
+        self.text[..self.floor_char_boundary(offset)]
+            .chars()
+            .count()
+    }
+
+    pub fn char_to_byte(&self, offset: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(offset)
+            .map_or(self.text.len(), |(i, _)| i)
+    }
+
+    // without the line ending
+    fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.text.len());
+
+        self.text[start..end].trim_end_matches(['\n', '\r'])
+    }
+
+    fn floor_char_boundary(&self, offset: usize) -> usize {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use PositionEncoding::*;
+
+    // `é` is 2 bytes and 1 UTF-16 unit, `😀` is 4 bytes and 2 UTF-16 units
+    const TEXT: &str = "aé😀b\r\nx\rλ😀\n";
+
+    #[test]
+    fn test_offset() {
+        let index = LineIndex::new(TEXT);
+        let offsets = |encoding| {
+            (0..8)
+                .map(|character| index.offset(Position::new(0, character), encoding))
+                .collect::<Vec<_>>()
+        };
+
+        // positions inside of a character are at its start, past the line
+        // end at the line end
+        assert_eq!(offsets(Utf8), [0, 1, 1, 3, 3, 3, 3, 7]);
+        assert_eq!(offsets(Utf16), [0, 1, 3, 3, 7, 8, 8, 8]);
+        assert_eq!(offsets(Utf32), [0, 1, 3, 7, 8, 8, 8, 8]);
+
+        assert_eq!(index.offset(Position::new(1, 0), Utf16), 10);
+        assert_eq!(index.offset(Position::new(2, 1), Utf16), 14);
+        assert_eq!(index.offset(Position::new(2, 3), Utf16), 18);
+        assert_eq!(index.offset(Position::new(3, 0), Utf16), 19);
+        assert_eq!(index.offset(Position::new(9, 0), Utf16), TEXT.len());
+    }
+
+    #[test]
+    fn test_position() {
+        let index = LineIndex::new(TEXT);
+        let positions = |encoding| {
+            [0, 1, 2, 3, 7, 8, 9, 10, 12, 19, 99]
+                .map(|offset| {
+                    let position = index.position(offset, encoding);
+                    (position.line, position.character)
+                })
+                .to_vec()
+        };
+
+        insta::assert_debug_snapshot!(
+            [positions(Utf8), positions(Utf16), positions(Utf32)].map(|p| format!("{:?}", p)),
+            @r#"
+        [
+            "[(0, 0), (0, 1), (0, 1), (0, 3), (0, 7), (0, 8), (0, 8), (1, 0), (2, 0), (3, 0), (3, 0)]",
+            "[(0, 0), (0, 1), (0, 1), (0, 2), (0, 4), (0, 5), (0, 5), (1, 0), (2, 0), (3, 0), (3, 0)]",
+            "[(0, 0), (0, 1), (0, 1), (0, 2), (0, 3), (0, 4), (0, 4), (1, 0), (2, 0), (3, 0), (3, 0)]",
+        ]
+        "#
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let index = LineIndex::new(TEXT);
+        assert_eq!(index.line_count(), 4);
+
+        for (offset, _) in TEXT.char_indices().filter(|(_, c)| !"\r\n".contains(*c)) {
+            for encoding in PositionEncoding::ALL {
+                let position = index.position(offset, encoding);
+                assert_eq!(index.offset(position, encoding), offset);
+            }
+        }
+
+        for offset in 0..TEXT.chars().count() {
+            let position = index.char_position(offset, Utf16);
+            assert_eq!(index.byte_to_char(index.char_to_byte(offset)), offset);
+            if !"\r\n".contains(TEXT.chars().nth(offset).unwrap()) {
+                assert_eq!(index.char_offset(position, Utf16), offset);
+            }
+        }
+
+        let position = index.convert(Position::new(2, 3), Utf16, Utf8);
+        assert_eq!(position, Position::new(2, 6));
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let negotiated = |kind: Option<&str>| {
+            PositionEncoding::negotiated(&ServerCapabilities {
+                position_encoding: kind.map(|kind| PositionEncodingKind::from(kind.to_string())),
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(negotiated(Some("utf-8")), Utf8);
+        assert_eq!(negotiated(Some("utf-32")), Utf32);
+        assert_eq!(negotiated(Some("utf-7")), Utf16);
+        assert_eq!(negotiated(None), Utf16);
+    }
+}
@@ -1,85 +1,338 @@
 use std::collections::HashSet;
+use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::Result;
 use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
-use lsp_types::{SymbolKind, Uri};
+use lsp_types::request::{DocumentSymbolRequest, GotoDefinition, References, Request};
+use lsp_types::{SymbolKind, TraceValue, Uri};
 use serde_json::json;
 
-use lsp_client::Client;
+use lsp_client::jsonrpc::{self, ErrorCode};
+use lsp_client::{
+    CancellationToken, InitializeBuilder, LineIndex, PositionEncoding, Readiness, Replay,
+    Supervisor, TraceLog, Transport,
+};
+
+const WATCHDOG_STALL: Duration = Duration::from_secs(10);
+
+// set by the SIGINT handler
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+enum TransportArg {
+    Stdio,
+    Connect(String),
+    Listen(String),
+    Unix(String),
+    Pipe(String),
+    WebSocket(String),
+    Replay(String),
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--connect <addr> | --listen <addr> | --unix <path> | --pipe <path> | --websocket <url> | --replay <file>] [--timeout <secs>] [--budget <secs>] [--index-timeout <secs>] [--settle <secs>] [--jobs <n>] [--trace <file>] [--record <file>] [--init <file>] <root-uri> [lsp-cmd] [lsp-cmd-args...]
+
+Transports:
+    (default)         Spawn <lsp-cmd> and talk to it over stdio
+    --connect <addr>  Connect to a server already listening on a TCP address
+    --listen <addr>   Spawn <lsp-cmd>, and wait for it to connect to a TCP address
+    --unix <path>     Connect to a server already listening on a unix socket
+    --pipe <path>     Spawn <lsp-cmd>, and wait for it to connect to a unix socket
+    --websocket <url> Connect to a server already listening on a ws:// url
+    --replay <file>   Play back a recording made with --record, instead of talking to a server
+
+Options:
+    --timeout <secs>  Give up on requests after this long, 0 waits forever (default: 60)
+    --budget <secs>   Skip symbols that take longer than this, 0 waits forever (default: 30)
+    --index-timeout <secs>
+                      Start scanning after this long, even if the server is still indexing (default: 300)
+    --settle <secs>   Count a server without progress or status as indexed after this long idle (default: 1)
+    --jobs <n>        Scan this many files at once (default: one per core, always one with --record or --replay)
+    --trace <file>    Append all LSP traffic to a trace log, as editors write it
+    --record <file>   Append all LSP traffic to a JSONL recording, for replaying in tests
+    --init <file>     Merge a JSON file into the initialize params, e.g. an editor's from its trace",
+        program
+    );
+    std::process::exit(1);
+}
 
 fn main() -> Result<()> {
     let args: Vec<_> = std::env::args().collect();
 
-    if args.len() < 3 {
-        eprintln!("Usage: {} <root-uri> <lsp-cmd> [lsp-cmd-args...]", args[0]);
-        std::process::exit(1);
+    let mut transport = TransportArg::Stdio;
+    let mut timeout = Some(Duration::from_secs(60));
+    let mut budget = Some(Duration::from_secs(30));
+    let mut index_timeout = Duration::from_secs(300);
+    let mut settle = Duration::from_secs(1);
+    let mut jobs = None;
+    let mut trace = None;
+    let mut record = None;
+    let mut init = None;
+    let mut positional = &args[1..];
+    while let [flag, value, rest @ ..] = positional {
+        // zero seconds means no limit
+        let secs = || {
+            let secs = value.parse().unwrap_or_else(|_| usage(&args[0]));
+            Some(Duration::from_secs(secs)).filter(|secs| !secs.is_zero())
+        };
+
+        match flag.as_str() {
+            "--connect" => transport = TransportArg::Connect(value.clone()),
+            "--listen" => transport = TransportArg::Listen(value.clone()),
+            "--unix" => transport = TransportArg::Unix(value.clone()),
+            "--pipe" => transport = TransportArg::Pipe(value.clone()),
+            "--websocket" => transport = TransportArg::WebSocket(value.clone()),
+            "--replay" => transport = TransportArg::Replay(value.clone()),
+            "--timeout" => timeout = secs(),
+            "--budget" => budget = secs(),
+            "--index-timeout" => index_timeout = secs().unwrap_or(Duration::MAX),
+            "--settle" => settle = secs().unwrap_or_default(),
+            "--jobs" => jobs = Some(value.parse().unwrap_or_else(|_| usage(&args[0]))),
+            "--trace" => trace = Some(value.clone()),
+            "--record" => record = Some(value.clone()),
+            "--init" => init = Some(value.clone()),
+            flag if flag.starts_with("--") => usage(&args[0]),
+            _ => break,
+        }
+        positional = rest;
+    }
+
+    let (root, cmd) = match positional {
+        [root, cmd @ ..] => (root, cmd),
+        [] => usage(&args[0]),
+    };
+
+    let needs_cmd = matches!(
+        transport,
+        TransportArg::Stdio | TransportArg::Listen(_) | TransportArg::Pipe(_)
+    );
+    if needs_cmd && cmd.is_empty() {
+        usage(&args[0]);
     }
 
-    let (root, cmd, args) = (&args[1], &args[2], &args[3..]);
+    // recordings play back in order, which only a single worker keeps to
+    if record.is_some() || matches!(transport, TransportArg::Replay(_)) {
+        jobs = Some(1);
+    }
 
     // read all lines from stdin
+    #[allow(clippy::mutable_key_type)]
     let project_files: HashSet<_> = std::io::stdin()
         .lock()
         .lines()
-        .filter_map(Result::ok)
+        .map_while(Result::ok)
         .filter_map(|line| Uri::from_str(&format!("{}/{}", root, line)).ok())
         .collect();
 
-    let root = Uri::from_str(&root)?;
-
-    eprintln!("     \x1b[1;32mRunning\x1b[0m `{} {}`", cmd, args.join(" "));
-    let mut child = Command::new(cmd)
-        .args(args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    // start stderr logging thread
-    let stderr = child.stderr.take().expect("Failed to take stderr");
-    std::thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => eprintln!("stderr: {}", line),
-                Err(err) => panic!("Error reading stderr: {}", err),
+    let root = Uri::from_str(root)?;
+
+    // bound once, restarted servers connect to it again
+    let listener = match &transport {
+        TransportArg::Listen(addr) => Some(TcpListener::bind(addr)?),
+        _ => None,
+    };
+
+    let cmd = cmd.to_vec();
+    let connect = move || -> Result<Transport> {
+        let transport = match &transport {
+            TransportArg::Stdio => Transport::stdio(&mut server_command(&cmd))?,
+            TransportArg::Connect(addr) => {
+                eprintln!("  \x1b[1;32mConnecting\x1b[0m to {}", addr);
+                Transport::tcp(addr)?
+            }
+            TransportArg::Listen(_) => {
+                let listener = listener.as_ref().unwrap();
+                let child = server_command(&cmd).spawn()?;
+                Transport::tcp_accept(listener)?.with_child(child)
+            }
+            #[cfg(unix)]
+            TransportArg::Unix(path) => {
+                eprintln!("  \x1b[1;32mConnecting\x1b[0m to {}", path);
+                Transport::unix(path)?
+            }
+            #[cfg(unix)]
+            TransportArg::Pipe(path) => {
+                let listener = UnixListener::bind(path)?;
+                let accepted = server_command(&cmd)
+                    .spawn()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|child| Ok(Transport::unix_accept(&listener)?.with_child(child)));
+
+                // the socket is only needed until the server connects, or fails to
+                std::fs::remove_file(path)?;
+                accepted?
+            }
+            TransportArg::WebSocket(url) => {
+                eprintln!("  \x1b[1;32mConnecting\x1b[0m to {}", url);
+                Transport::websocket(url)?
+            }
+            TransportArg::Replay(path) => Replay::file(path)?.transport(),
+            #[cfg(not(unix))]
+            TransportArg::Unix(_) | TransportArg::Pipe(_) => {
+                anyhow::bail!("unix sockets are not supported on this platform")
+            }
+        };
+
+        match &record {
+            Some(path) => {
+                let file = File::options().create(true).append(true).open(path)?;
+                Ok(transport.record(file))
+            }
+            None => Ok(transport),
+        }
+    };
+
+    // tracked on every server, restarted ones start indexing again
+    let readiness = Readiness::new().with_settle(settle);
+
+    let tracing = trace.is_some();
+    let supervisor = Supervisor::new(connect)?
+        .with_setup({
+            let readiness = readiness.clone();
+            move |client| {
+                // before initializing, to see all of the server's progress
+                readiness.attach(client);
+
+                // start stderr logging thread
+                if let Some(stderr) = client.with_child(|child| child.stderr.take()).flatten() {
+                    std::thread::spawn(move || {
+                        let reader = BufReader::new(stderr);
+                        for line in reader.lines() {
+                            match line {
+                                Ok(line) => eprintln!("stderr: {}", line),
+                                Err(err) => panic!("Error reading stderr: {}", err),
+                            }
+                        }
+                    });
+                }
+
+                if let Some(path) = &trace {
+                    match TraceLog::file(path, TraceValue::Verbose) {
+                        Ok(log) => client.set_trace_log(Some(log)),
+                        Err(err) => eprintln!("     \x1b[1;33mWarning\x1b[0m {:#}", err),
+                    }
+                }
+
+                client.set_timeout(timeout);
+
+                // say something, instead of silently hanging
+                client.watchdog(WATCHDOG_STALL, |stall| {
+                    eprintln!("     \x1b[1;33mWarning\x1b[0m {}", stall);
+                });
+            }
+        })
+        .with_restart_log(|restart| {
+            eprintln!("  \x1b[1;33mRestarting\x1b[0m {}", restart);
+        });
+
+    // shut the server down on Ctrl-C, instead of orphaning it
+    on_interrupt();
+    std::thread::spawn({
+        let supervisor = supervisor.clone();
+        move || loop {
+            std::thread::sleep(Duration::from_millis(100));
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                eprintln!(" \x1b[1;33mInterrupted\x1b[0m Shutting down LSP server...");
+                let _ = supervisor.shutdown();
+                std::process::exit(130);
             }
         }
     });
 
-    let input = BufReader::new(child.stdout.take().expect("Failed to take stdout"));
-    let output = child.stdin.take().expect("Failed to take stdin");
+    let mut params = InitializeBuilder::new(root.clone());
+    if let Some(init) = &init {
+        params = params.merge_file(init)?;
+    }
 
-    let mut client = Client::new(Box::new(input), Box::new(output));
+    // the editor's params, but for this workspace and process
+    let mut params = params
+        .with_root(root.clone())
+        .with_client_info("code-graph", Some(env!("CARGO_PKG_VERSION")))
+        .with_process_id(Some(std::process::id()))
+        .build();
+    // tracing is turned on below, if asked for
+    params.trace = None;
 
-    let capabilities = client.initialize(root.clone())?;
+    supervisor.initialize_with(params)?;
 
-    if capabilities.document_symbol_provider.is_none() {
-        panic!("Server is not 'textDocument/documentSymbol' provider");
+    // have the server explain itself in the trace too
+    if tracing {
+        supervisor.run(|client| client.set_trace(TraceValue::Verbose))?;
     }
 
-    if capabilities.references_provider.is_none() {
-        panic!("Server is not 'textDocument/reference' provider");
-    }
+    let options = Options {
+        budget,
+        index_timeout,
+        jobs,
+    };
+    let graph = graph(&supervisor, &readiness, &root, &project_files, &options)?;
 
-    if capabilities.definition_provider.is_none() {
-        panic!("Server is not 'textDocument/definition' provider");
-    }
+    println!("{}", serde_json::to_string_pretty(&graph)?);
+
+    supervisor.shutdown()?;
+
+    Ok(())
+}
+
+// see the usage for each
+struct Options {
+    budget: Option<Duration>,
+    index_timeout: Duration,
+    jobs: Option<usize>,
+}
 
-    for file in &project_files {
+// which project files reference which, by their path from the root
+#[allow(clippy::mutable_key_type)]
+fn graph(
+    supervisor: &Supervisor,
+    readiness: &Readiness,
+    root: &Uri,
+    project_files: &HashSet<Uri>,
+    options: &Options,
+) -> Result<serde_json::Value> {
+    // in a fixed order, so runs can be compared and replayed
+    let mut sorted_files: Vec<_> = project_files.iter().collect();
+    sorted_files.sort_by_key(|file| file.as_str());
+
+    for file in &sorted_files {
         eprintln!("    \x1b[1;32mIndexing\x1b[0m {}", file.as_str());
 
-        client.open(file, &std::fs::read_to_string(file.path().as_str())?)?;
+        supervisor.open(file, &std::fs::read_to_string(file.path().as_str())?)?;
     }
 
     eprintln!("     \x1b[1;32mWaiting\x1b[0m For LSP server to index code...");
-    std::thread::sleep(std::time::Duration::from_secs(5));
+    if !readiness.wait(options.index_timeout) {
+        eprintln!(
+            "     \x1b[1;33mWarning\x1b[0m server still indexing after {:?}, scanning anyway",
+            options.index_timeout
+        );
+    }
 
-    let mut nodes = HashSet::new();
-    let mut edges = HashSet::new();
+    // servers may register capabilities only once initialized
+    let capabilities = supervisor.run(|client| Ok(client.capabilities()))?;
+    for method in [
+        DocumentSymbolRequest::METHOD,
+        References::METHOD,
+        GotoDefinition::METHOD,
+    ] {
+        if !capabilities.supports(method) {
+            anyhow::bail!("Server is not {:?} provider", method);
+        }
+    }
+
+    let nodes = Mutex::new(HashSet::new());
+    let edges = Mutex::new(HashSet::new());
 
     // only use these kinds of symbols
     let symbol_mask = [
@@ -97,37 +350,118 @@ fn main() -> Result<()> {
         .progress_chars("=> "),
     );
 
-    for file in &project_files {
-        let node = file.as_str().strip_prefix(root.as_str()).unwrap();
-        nodes.insert(node);
-
-        bar.println(format!("     \x1b[1;32mScanned\x1b[0m {}", node));
-        bar.inc(1);
-
-        for symbol in &client.symbols(file)? {
-            if !symbol_mask.contains(&symbol.kind) {
+    let files = Mutex::new(sorted_files.iter().copied());
+
+    // scan several files at once, the client can wait for many responses
+    let scan = || -> Result<()> {
+        loop {
+            let Some(file) = files.lock().unwrap().next() else {
+                return Ok(());
+            };
+
+            let node = file.as_str().strip_prefix(root.as_str()).unwrap();
+            nodes.lock().unwrap().insert(node);
+
+            // the server's positions, in the encoding it picked. a restarted
+            // server may have registered other capabilities
+            let (supported, text, encoding) = supervisor.run(|client| {
+                let supported = client.capabilities().supports_document(
+                    DocumentSymbolRequest::METHOD,
+                    file,
+                    None,
+                );
+                let text = client.document_text(file).unwrap_or_default();
+                Ok((supported, text, client.position_encoding()))
+            })?;
+
+            if !supported {
+                bar.println(format!(
+                    "     \x1b[1;33mSkipped\x1b[0m {}, not supported by the server",
+                    node
+                ));
+                bar.inc(1);
                 continue;
             }
+            let index = LineIndex::new(text);
 
-            bar.set_message(format!("{:?} {}", symbol.kind, symbol.name));
-
-            // ignore symbols defined outside of current file
-            if !client.definitions(file, symbol)?.iter().any(|d| d == file) {
-                continue;
-            }
+            // line and column in chars, as editors show them
+            let place = |position| {
+                let position = index.convert(position, encoding, PositionEncoding::Utf32);
+                format!("{}:{}:{}", node, position.line + 1, position.character + 1)
+            };
 
-            for reference in &client.references(file, symbol)? {
-                // ignore references outside of project files
-                let Some(reference) = project_files.get(reference) else {
+            for symbol in &supervisor.run(|client| client.symbols(file))? {
+                if !symbol_mask.contains(&symbol.kind) {
                     continue;
+                }
+
+                bar.set_message(format!("{:?} {}", symbol.kind, symbol.name));
+
+                // give up on symbols that take too long
+                let token = CancellationToken::new();
+                if let Some(budget) = options.budget {
+                    token.cancel_after(budget);
+                }
+
+                // retried on a restarted server if it crashes
+                let references = || {
+                    supervisor.run(|client| {
+                        let client = client.with_token(&token);
+
+                        // ignore symbols defined outside of current file
+                        if !client.definitions(file, symbol)?.iter().any(|d| d == file) {
+                            return Ok(vec![]);
+                        }
+
+                        client.references(file, symbol)
+                    })
                 };
 
-                let reference = reference.as_str().strip_prefix(root.as_str()).unwrap();
+                let references = match references() {
+                    Err(err) if is_cancelled(&err) => {
+                        bar.println(format!(
+                            "     \x1b[1;33mSkipped\x1b[0m {} at {}, over budget",
+                            symbol.name,
+                            place(symbol.selection_range.start)
+                        ));
+                        continue;
+                    }
+                    references => references?,
+                };
+
+                // stop the budget timer
+                token.cancel();
+
+                for reference in &references {
+                    // ignore references outside of project files
+                    let Some(reference) = project_files.get(reference) else {
+                        continue;
+                    };
 
-                edges.insert((reference, node));
+                    let reference = reference.as_str().strip_prefix(root.as_str()).unwrap();
+
+                    edges.lock().unwrap().insert((reference, node));
+                }
             }
+
+            bar.println(format!("     \x1b[1;32mScanned\x1b[0m {}", node));
+            bar.inc(1);
         }
-    }
+    };
+
+    let workers = options
+        .jobs
+        .filter(|&jobs| jobs > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..workers).map(|_| scope.spawn(scan)).collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    let (nodes, edges) = (nodes.into_inner().unwrap(), edges.into_inner().unwrap());
 
     bar.println(format!(
         "    \x1b[1;32mFinished\x1b[0m in {}",
@@ -135,12 +469,137 @@ fn main() -> Result<()> {
     ));
     bar.finish_and_clear();
 
-    let graph = json!({
+    Ok(json!({
         "nodes": nodes,
         "edges": edges,
-    });
+    }))
+}
 
-    println!("{}", serde_json::to_string_pretty(&graph)?);
+#[cfg(unix)]
+fn on_interrupt() {
+    extern "C" fn handle(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
 
-    Ok(())
+    // SAFETY: the handler only stores to an atomic, which is signal safe
+    unsafe {
+        libc::signal(libc::SIGINT, handle as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn on_interrupt() {}
+
+fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<jsonrpc::Error>()
+        .is_some_and(|err| err.code == ErrorCode::RequestCancelled)
+}
+
+fn server_command(cmd: &[String]) -> Command {
+    eprintln!("     \x1b[1;32mRunning\x1b[0m `{}`", cmd.join(" "));
+
+    let mut command = Command::new(&cmd[0]);
+    // stdout is for the graph, servers talking over a socket log to stderr
+    command
+        .args(&cmd[1..])
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .stderr(Stdio::piped());
+
+    // Ctrl-C only reaches us, the server is shut down gracefully
+    #[cfg(unix)]
+    command.process_group(0);
+
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_client::MockServer;
+    use lsp_types::{DocumentSymbol, Location, Position, Range};
+
+    use super::*;
+
+    #[test]
+    fn test_graph() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(
+            dir.path().join("src/main.rs"),
+            "mod util;\n\nfn main() {\n    util::helper();\n}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("src/util.rs"), "pub fn helper() {}\n").unwrap();
+
+        let root = Uri::from_str(&format!("file://{}", dir.path().display())).unwrap();
+        let file = |path| Uri::from_str(&format!("{}/{}", root.as_str(), path)).unwrap();
+        let (main, util) = (file("src/main.rs"), file("src/util.rs"));
+
+        // `helper` is called from main.rs
+        let at = |uri: &Uri, line, character| {
+            let start = Position::new(line, character);
+            Location::new(
+                uri.clone(),
+                Range::new(start, Position::new(line, character + 6)),
+            )
+        };
+        let helper = at(&util, 0, 7);
+        let symbol: DocumentSymbol = serde_json::from_value(json!({
+            "name": "helper",
+            "kind": SymbolKind::FUNCTION,
+            "range": helper.range,
+            "selectionRange": helper.range,
+        }))
+        .unwrap();
+
+        let readiness = Readiness::new().with_settle(Duration::ZERO);
+        let supervisor = Supervisor::new({
+            let (main, util) = (main.clone(), util.clone());
+            move || {
+                let start = helper.range.start;
+                let server = MockServer::new()
+                    .with_symbols(&util, vec![symbol.clone()])
+                    .with_definitions(&util, start, vec![helper.clone()])
+                    .with_references(&util, start, vec![helper.clone(), at(&main, 3, 10)]);
+                Ok(server.start().0)
+            }
+        })
+        .unwrap()
+        .with_setup({
+            let readiness = readiness.clone();
+            move |client| readiness.attach(client)
+        });
+        supervisor.initialize(root.clone()).unwrap();
+
+        let options = Options {
+            budget: None,
+            index_timeout: Duration::from_secs(10),
+            jobs: Some(2),
+        };
+        #[allow(clippy::mutable_key_type)]
+        let project_files = HashSet::from([main, util]);
+        let mut graph = graph(&supervisor, &readiness, &root, &project_files, &options).unwrap();
+        supervisor.shutdown().unwrap();
+
+        for key in ["nodes", "edges"] {
+            graph[key]
+                .as_array_mut()
+                .unwrap()
+                .sort_by_key(|value| value.to_string());
+        }
+        insta::assert_json_snapshot!(graph, @r#"
+        {
+          "edges": [
+            [
+              "/src/main.rs",
+              "/src/util.rs"
+            ]
+          ],
+          "nodes": [
+            "/src/main.rs",
+            "/src/util.rs"
+          ]
+        }
+        "#);
+    }
 }
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use lsp_types::notification::{Exit, Initialized, Notification};
+use lsp_types::request::*;
+use lsp_types::*;
+use serde_json::Value;
+
+use crate::handlers::Handlers;
+use crate::jsonrpc::{self, ErrorCode, Id, Message};
+use crate::transport::{MessageReader, MessageWriter, Transport};
+
+// serves `documentSymbol`, `references` and `definition` from a fake
+// workspace, and anything else with the handlers set with `on_request`
+pub struct MockServer {
+    capabilities: ServerCapabilities,
+    handlers: Handlers,
+    delays: HashMap<&'static str, Duration>,
+    // sent once the client is initialized
+    notifications: Vec<jsonrpc::Notification<Value>>,
+    workspace: Workspace,
+}
+
+#[derive(Default)]
+struct Workspace {
+    symbols: HashMap<Uri, Vec<DocumentSymbol>>,
+    references: HashMap<(Uri, Position), Vec<Location>>,
+    definitions: HashMap<(Uri, Position), Vec<Location>>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::INCREMENTAL,
+                )),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                definition_provider: Some(OneOf::Left(true)),
+                ..Default::default()
+            },
+            handlers: Handlers::default(),
+            delays: HashMap::new(),
+            notifications: vec![],
+            workspace: Workspace::default(),
+        }
+    }
+
+    pub fn with_capabilities(mut self, capabilities: ServerCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_symbols(mut self, uri: &Uri, symbols: Vec<DocumentSymbol>) -> Self {
+        self.workspace.symbols.insert(uri.clone(), symbols);
+        self
+    }
+
+    pub fn with_references(
+        mut self,
+        uri: &Uri,
+        position: Position,
+        references: Vec<Location>,
+    ) -> Self {
+        self.workspace
+            .references
+            .insert((uri.clone(), position), references);
+        self
+    }
+
+    pub fn with_definitions(
+        mut self,
+        uri: &Uri,
+        position: Position,
+        definitions: Vec<Location>,
+    ) -> Self {
+        self.workspace
+            .definitions
+            .insert((uri.clone(), position), definitions);
+        self
+    }
+
+    pub fn on_request<R: Request>(
+        mut self,
+        handler: impl Fn(R::Params) -> Result<R::Result, jsonrpc::Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.handlers.insert::<R>(handler);
+        self
+    }
+
+    pub fn with_error<R: Request>(self, err: jsonrpc::Error) -> Self {
+        self.on_request::<R>(move |_| Err(err.clone()))
+    }
+
+    pub fn with_delay<R: Request>(mut self, delay: Duration) -> Self {
+        self.delays.insert(R::METHOD, delay);
+        self
+    }
+
+    pub fn with_notification<N: Notification>(mut self, params: N::Params) -> Self {
+        self.notifications.push(notification::<N>(params));
+        self
+    }
+
+    pub fn start(mut self) -> (Transport, MockHandle) {
+        let builtin = self.builtin();
+
+        let (client_tx, server_rx) = mpsc::channel::<String>();
+        let (server_tx, client_rx) = mpsc::channel::<Vec<u8>>();
+
+        let handle = MockHandle {
+            link: Arc::new(Mutex::new(Some(Link {
+                tx: server_tx.clone(),
+                pending: HashMap::new(),
+            }))),
+            received: Arc::default(),
+            request_id_counter: Arc::default(),
+        };
+
+        std::thread::spawn({
+            let handle = handle.clone();
+            move || {
+                self.serve(builtin, server_rx, server_tx, &handle);
+                // the client sees the server go, and requests to it fail
+                handle.link.lock().unwrap().take();
+            }
+        });
+
+        let transport = Transport::new(ChannelReader(client_rx), ChannelWriter(client_tx));
+
+        (transport, handle)
+    }
+
+    // used when the test didn't set its own
+    fn builtin(&mut self) -> Handlers {
+        let capabilities = self.capabilities.clone();
+        let workspace = Arc::new(std::mem::take(&mut self.workspace));
+
+        let mut builtin = Handlers::default();
+        builtin.insert::<Initialize>(move |_| {
+            Ok(InitializeResult {
+                capabilities: capabilities.clone(),
+                server_info: Some(ServerInfo {
+                    name: "mock".to_string(),
+                    version: None,
+                }),
+            })
+        });
+        builtin.insert::<Shutdown>(|_| Ok(()));
+
+        builtin.insert::<DocumentSymbolRequest>({
+            let workspace = workspace.clone();
+            move |params| {
+                let symbols = workspace.symbols.get(&params.text_document.uri);
+                Ok(symbols.map(|symbols| DocumentSymbolResponse::Nested(symbols.clone())))
+            }
+        });
+
+        builtin.insert::<References>({
+            let workspace = workspace.clone();
+            move |params| {
+                let position = params.text_document_position;
+                let key = (position.text_document.uri, position.position);
+                Ok(workspace.references.get(&key).cloned())
+            }
+        });
+
+        builtin.insert::<GotoDefinition>(move |params| {
+            let position = params.text_document_position_params;
+            let key = (position.text_document.uri, position.position);
+            Ok(workspace
+                .definitions
+                .get(&key)
+                .map(|definitions| GotoDefinitionResponse::Array(definitions.clone())))
+        });
+
+        builtin
+    }
+
+    fn serve(
+        self,
+        builtin: Handlers,
+        rx: mpsc::Receiver<String>,
+        tx: mpsc::Sender<Vec<u8>>,
+        handle: &MockHandle,
+    ) {
+        for msg in rx {
+            // batches are answered one message at a time
+            let messages = match serde_json::from_str(&msg) {
+                Ok(Value::Array(batch)) => batch,
+                Ok(value) => vec![value],
+                Err(_) => continue,
+            };
+
+            for msg in messages {
+                handle.received.lock().unwrap().push(msg.clone());
+
+                match Message::from_value(msg) {
+                    Ok(Message::Request(request)) => self.respond(&builtin, request, &tx),
+                    Ok(Message::Notification(notification)) => match notification.method.as_str() {
+                        Initialized::METHOD => {
+                            for notification in &self.notifications {
+                                let _ = tx.send(serde_json::to_vec(notification).unwrap());
+                            }
+                        }
+                        Exit::METHOD => return,
+                        _ => {}
+                    },
+                    Ok(Message::Response(response)) => {
+                        let pending = handle
+                            .link
+                            .lock()
+                            .unwrap()
+                            .as_mut()
+                            .and_then(|link| link.pending.remove(&response.id));
+                        if let Some(tx) = pending {
+                            let _ = tx.send(response.result);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    fn respond(
+        &self,
+        builtin: &Handlers,
+        request: jsonrpc::Request<Value>,
+        tx: &mpsc::Sender<Vec<u8>>,
+    ) {
+        let handler = self
+            .handlers
+            .get(&request.method)
+            .or_else(|| builtin.get(&request.method));
+        let delay = self.delays.get(request.method.as_str()).copied();
+        let tx = tx.clone();
+
+        let respond = move || {
+            if let Some(delay) = delay {
+                std::thread::sleep(delay);
+            }
+
+            let result = match handler {
+                Some(handler) => handler(request.params),
+                None => Err(jsonrpc::Error::new(
+                    ErrorCode::MethodNotFound,
+                    format!("unhandled method {:?}", request.method),
+                )),
+            };
+
+            let response = jsonrpc::Response {
+                jsonrpc: "2.0".to_string(),
+                result,
+                id: request.id,
+            };
+            let _ = tx.send(serde_json::to_vec(&response).unwrap());
+        };
+
+        // delayed responses don't hold up the others
+        match delay {
+            Some(_) => drop(std::thread::spawn(respond)),
+            None => respond(),
+        }
+    }
+}
+
+type Pending = mpsc::Sender<Result<Value, jsonrpc::Error>>;
+
+#[derive(Clone)]
+pub struct MockHandle {
+    // `None` once the mock server stopped
+    link: Arc<Mutex<Option<Link>>>,
+    received: Arc<Mutex<Vec<Value>>>,
+    request_id_counter: Arc<AtomicI64>,
+}
+
+struct Link {
+    tx: mpsc::Sender<Vec<u8>>,
+    pending: HashMap<Id, Pending>,
+}
+
+impl MockHandle {
+    pub fn notify<N: Notification>(&self, params: N::Params) {
+        if let Some(link) = self.link.lock().unwrap().as_ref() {
+            let _ = link
+                .tx
+                .send(serde_json::to_vec(&notification::<N>(params)).unwrap());
+        }
+    }
+
+    pub fn request<R: Request>(&self, params: R::Params) -> Result<R::Result> {
+        let id = Id::String(format!(
+            "mock-{}",
+            self.request_id_counter.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let request = jsonrpc::Request {
+            jsonrpc: "2.0".to_string(),
+            method: R::METHOD.to_string(),
+            params: Some(serde_json::to_value(params)?),
+            id: id.clone(),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut link = self.link.lock().unwrap();
+            let Some(link) = link.as_mut() else {
+                anyhow::bail!("mock server stopped");
+            };
+
+            link.tx
+                .send(serde_json::to_vec(&request)?)
+                .map_err(|_| anyhow::anyhow!("client is gone"))?;
+            link.pending.insert(id, tx);
+        }
+
+        let result = rx.recv().context("mock server stopped")??;
+
+        serde_json::from_value(result).context("deserializing response result")
+    }
+
+    pub fn received(&self) -> Vec<Value> {
+        self.received.lock().unwrap().clone()
+    }
+
+    pub fn methods(&self) -> Vec<String> {
+        self.received()
+            .iter()
+            .filter_map(|msg| msg["method"].as_str().map(str::to_string))
+            .collect()
+    }
+}
+
+fn notification<N: Notification>(params: N::Params) -> jsonrpc::Notification<Value> {
+    jsonrpc::Notification {
+        jsonrpc: "2.0".to_string(),
+        method: N::METHOD.to_string(),
+        params: Some(serde_json::to_value(params).unwrap()),
+    }
+}
+
+struct ChannelReader(mpsc::Receiver<Vec<u8>>);
+
+impl MessageReader for ChannelReader {
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        // the server is gone once every sender is dropped
+        Ok(self.0.recv().ok())
+    }
+}
+
+struct ChannelWriter(mpsc::Sender<String>);
+
+impl MessageWriter for ChannelWriter {
+    fn write_message(&mut self, msg: &str) -> Result<()> {
+        self.0
+            .send(msg.to_string())
+            .map_err(|_| anyhow::anyhow!("mock server is gone"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Instant;
+
+    use lsp_types::notification::{LogMessage, ShowMessage};
+
+    use super::*;
+    use crate::Client;
+
+    fn message(text: &str) -> ShowMessageParams {
+        ShowMessageParams {
+            typ: MessageType::INFO,
+            message: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = jsonrpc::Error::new(ErrorCode::ContentModified, "try again");
+        let (transport, _) = MockServer::new().with_error::<HoverRequest>(err).start();
+        let client = Client::with_transport(transport);
+
+        let params = serde_json::from_value(serde_json::json!({
+            "textDocument": {"uri": "file:///a.rs"},
+            "position": {"line": 0, "character": 0},
+        }))
+        .unwrap();
+        let hover = client.request::<HoverRequest>(Some(params)).unwrap_err();
+        let rename = client
+            .request::<WillRenameFiles>(Some(RenameFilesParams { files: vec![] }))
+            .unwrap_err();
+
+        insta::assert_snapshot!(format!("{:#}\n{:#}", hover, rename), @r#"
+        getting response result: Error -32801: try again
+        getting response result: Error -32601: unhandled method "workspace/willRenameFiles"
+        "#);
+    }
+
+    #[test]
+    fn test_delay() {
+        let (transport, _) = MockServer::new()
+            .with_delay::<Shutdown>(Duration::from_secs(1))
+            .start();
+        let client = Client::with_transport(transport);
+
+        let started = Instant::now();
+        let slow = std::thread::spawn({
+            let client = client.clone();
+            move || client.request::<Shutdown>(None)
+        });
+
+        // not stuck behind the delayed response
+        client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+        assert!(!slow.is_finished());
+
+        slow.join().unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_stopped() {
+        let (transport, handle) = MockServer::new().start();
+        let client = Client::with_transport(transport);
+
+        client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+        client.shutdown().unwrap();
+
+        // gone with the client, instead of waiting for it forever
+        let err = handle.request::<WorkspaceFoldersRequest>(()).unwrap_err();
+        insta::assert_snapshot!(err, @"mock server stopped");
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (transport, handle) = MockServer::new()
+            .with_notification::<ShowMessage>(message("ready"))
+            .start();
+        let client = Client::with_transport(transport);
+        let messages = client.subscribe::<ShowMessage>();
+        let logs = client.subscribe::<LogMessage>();
+
+        client
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+        assert_eq!(messages.recv().unwrap().message, "ready");
+
+        handle.notify::<LogMessage>(LogMessageParams {
+            typ: MessageType::LOG,
+            message: "indexing".to_string(),
+        });
+        assert_eq!(logs.recv().unwrap().message, "indexing");
+
+        insta::assert_debug_snapshot!(handle.methods(), @r#"
+        [
+            "initialize",
+            "initialized",
+        ]
+        "#);
+    }
+}
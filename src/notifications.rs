@@ -0,0 +1,230 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+use lsp_types::notification::Notification;
+use serde_json::Value;
+
+use crate::jsonrpc;
+
+// notifications nobody subscribed to, the oldest are dropped first
+const INBOX_CAPACITY: usize = 1024;
+
+// notifications without subscribers are kept in an inbox for the first
+// subscriber of their method. subscribers are called outside of the lock,
+// so they may subscribe too
+#[derive(Default)]
+pub(crate) struct Notifications {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: HashMap<&'static str, Vec<Arc<Subscriber>>>,
+    inbox: VecDeque<jsonrpc::Notification<Value>>,
+}
+
+struct Subscriber {
+    // `false` once the subscriber is gone
+    deliver: Box<dyn Fn(Option<Value>) -> bool + Send + Sync>,
+    // params from the inbox, delivered before any later ones
+    backlog: Mutex<Vec<Option<Value>>>,
+    alive: AtomicBool,
+}
+
+impl Subscriber {
+    fn deliver(&self, params: Option<Option<Value>>) -> bool {
+        let mut backlog = self.backlog.lock().unwrap();
+
+        let alive = backlog
+            .drain(..)
+            .chain(params)
+            .all(|params| self.alive.load(Ordering::SeqCst) && (self.deliver)(params));
+        if !alive {
+            self.alive.store(false, Ordering::SeqCst);
+        }
+
+        alive
+    }
+}
+
+impl Notifications {
+    pub(crate) fn on<N: Notification>(&self, callback: impl Fn(N::Params) + Send + Sync + 'static) {
+        self.insert::<N>(move |params| {
+            callback(params);
+            true
+        });
+    }
+
+    pub(crate) fn subscribe<N: Notification>(&self) -> mpsc::Receiver<N::Params> {
+        let (tx, rx) = mpsc::channel();
+        self.insert::<N>(move |params| tx.send(params).is_ok());
+
+        rx
+    }
+
+    fn insert<N: Notification>(&self, deliver: impl Fn(N::Params) -> bool + Send + Sync + 'static) {
+        // params that don't match the method's type are dropped
+        let deliver =
+            move |params: Option<Value>| match serde_json::from_value(params.unwrap_or_default()) {
+                Ok(params) => deliver(params),
+                Err(_) => true,
+            };
+
+        let subscriber = {
+            let mut state = self.state.lock().unwrap();
+
+            // catch up on everything that arrived before
+            let (buffered, inbox): (VecDeque<_>, _) = std::mem::take(&mut state.inbox)
+                .into_iter()
+                .partition(|notification| notification.method == N::METHOD);
+            state.inbox = inbox;
+
+            let subscriber = Arc::new(Subscriber {
+                deliver: Box::new(deliver),
+                backlog: Mutex::new(buffered.into_iter().map(|n| n.params).collect()),
+                alive: AtomicBool::new(true),
+            });
+
+            let subscribers = state.subscribers.entry(N::METHOD).or_default();
+            subscribers.push(subscriber.clone());
+
+            subscriber
+        };
+
+        // on the caller's thread, unless a new notification got to it first
+        subscriber.deliver(None);
+    }
+
+    pub(crate) fn dispatch(&self, notification: jsonrpc::Notification<Value>) {
+        let subscribers = {
+            let mut state = self.state.lock().unwrap();
+            match state.subscribers.get_mut(notification.method.as_str()) {
+                Some(subscribers) => {
+                    subscribers.retain(|subscriber| subscriber.alive.load(Ordering::SeqCst));
+                    subscribers.clone()
+                }
+                None => vec![],
+            }
+        };
+
+        let mut delivered = false;
+        for subscriber in subscribers {
+            delivered |= subscriber.deliver(Some(notification.params.clone()));
+        }
+
+        // everyone unsubscribed, so nobody got it
+        if !delivered {
+            let mut state = self.state.lock().unwrap();
+            state.subscribers.retain(|_, subscribers| {
+                subscribers.iter().any(|s| s.alive.load(Ordering::SeqCst))
+            });
+
+            if state.inbox.len() == INBOX_CAPACITY {
+                state.inbox.pop_front();
+            }
+            state.inbox.push_back(notification);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use lsp_types::notification::{LogMessage, PublishDiagnostics};
+    use serde_json::json;
+
+    use super::*;
+
+    fn notification(method: &str, params: Value) -> jsonrpc::Notification<Value> {
+        jsonrpc::Notification {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params: Some(params),
+        }
+    }
+
+    fn log(message: &str) -> jsonrpc::Notification<Value> {
+        notification("window/logMessage", json!({"type": 3, "message": message}))
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let notifications = Notifications::default();
+
+        notifications.dispatch(log("early"));
+        notifications.dispatch(notification(
+            "textDocument/publishDiagnostics",
+            json!({"uri": "file:///a.rs", "diagnostics": []}),
+        ));
+
+        let logs = notifications.subscribe::<LogMessage>();
+        notifications.dispatch(log("late"));
+        notifications.dispatch(notification("window/logMessage", json!({})));
+
+        let messages: Vec<_> = logs.try_iter().map(|params| params.message).collect();
+        assert_eq!(messages, vec!["early", "late"]);
+
+        // the diagnostics were kept for their own subscriber
+        let uris = Arc::new(Mutex::new(vec![]));
+        notifications.on::<PublishDiagnostics>({
+            let uris = uris.clone();
+            move |params| uris.lock().unwrap().push(params.uri.to_string())
+        });
+        assert_eq!(*uris.lock().unwrap(), vec!["file:///a.rs"]);
+
+        // dropped receivers are unsubscribed, later logs go to the inbox
+        drop(logs);
+        notifications.dispatch(log("unread"));
+        notifications.dispatch(log("again"));
+        let state = notifications.state.lock().unwrap();
+        assert!(!state.subscribers.contains_key(LogMessage::METHOD));
+        drop(state);
+
+        let messages: Vec<_> = notifications
+            .subscribe::<LogMessage>()
+            .try_iter()
+            .map(|params| params.message)
+            .collect();
+        assert_eq!(messages, vec!["unread", "again"]);
+    }
+
+    #[test]
+    fn test_inbox_capacity() {
+        let notifications = Notifications::default();
+
+        for i in 0..INBOX_CAPACITY + 1 {
+            notifications.dispatch(log(&i.to_string()));
+        }
+
+        let logs = notifications.subscribe::<LogMessage>();
+        let first = logs.try_iter().next().unwrap();
+
+        assert_eq!(first.message, "1");
+        assert_eq!(logs.try_iter().count(), INBOX_CAPACITY - 1);
+    }
+
+    #[test]
+    fn test_subscribe_in_callback() {
+        let notifications = Arc::new(Notifications::default());
+        let (tx, rx) = mpsc::channel();
+
+        notifications.dispatch(log("early"));
+        notifications.on::<LogMessage>({
+            let notifications = notifications.clone();
+            let tx = Mutex::new(tx);
+            move |params| {
+                let diagnostics = notifications.subscribe::<PublishDiagnostics>();
+                tx.lock()
+                    .unwrap()
+                    .send((params.message, diagnostics))
+                    .unwrap();
+            }
+        });
+        notifications.dispatch(log("late"));
+
+        let messages: Vec<_> = rx.try_iter().map(|(message, _)| message).collect();
+        assert_eq!(messages, vec!["early", "late"]);
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::{HashMap, VecDeque};
+
+use lsp_types::notification::{Cancel, Notification};
+use lsp_types::request::Request;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::codec::{self, Decoder, FrameError};
+use crate::jsonrpc::{self, Id, MalformedMessage, Message};
+
+// the LSP client protocol, without any IO
+#[derive(Default)]
+pub struct Protocol {
+    decoder: Decoder,
+    outgoing: VecDeque<String>,
+    events: VecDeque<Event>,
+    pending: HashMap<Id, String>,
+    request_id_counter: i64,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Response {
+        id: Id,
+        method: String,
+        result: Result<Value, jsonrpc::Error>,
+    },
+    Request(jsonrpc::Request<Value>),
+    Notification(jsonrpc::Notification<Value>),
+    Error(ProtocolError),
+}
+
+impl Protocol {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn notify<N: Notification>(&mut self, params: Option<N::Params>) -> serde_json::Result<()> {
+        let notification = jsonrpc::Notification {
+            jsonrpc: "2.0".to_string(),
+            method: N::METHOD.to_string(),
+            params,
+        };
+
+        self.queue(&notification)
+    }
+
+    pub fn request<R: Request>(&mut self, params: Option<R::Params>) -> serde_json::Result<Id> {
+        let request = self.new_request::<R>(params);
+        self.queue(&request)?;
+
+        Ok(self.track(request))
+    }
+
+    // returns the ids in the same order as `params`
+    pub fn request_batch<R: Request>(
+        &mut self,
+        params: Vec<Option<R::Params>>,
+    ) -> serde_json::Result<Vec<Id>> {
+        // an empty batch is an invalid request, so don't bother the server
+        if params.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let batch = jsonrpc::Batch(
+            params
+                .into_iter()
+                .map(|params| self.new_request::<R>(params))
+                .collect(),
+        );
+
+        self.queue(&batch)?;
+
+        Ok(batch.0.into_iter().map(|r| self.track(r)).collect())
+    }
+
+    pub fn respond(
+        &mut self,
+        id: Id,
+        result: Result<impl Serialize, jsonrpc::Error>,
+    ) -> serde_json::Result<()> {
+        let result = match result {
+            Ok(result) => Ok(serde_json::to_value(result)?),
+            Err(err) => Err(err),
+        };
+
+        let response = jsonrpc::Response {
+            jsonrpc: "2.0".to_string(),
+            result,
+            id,
+        };
+
+        self.queue(&response)
+    }
+
+    pub fn is_pending(&self, id: &Id) -> bool {
+        self.pending.contains_key(id)
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = (&Id, &str)> {
+        self.pending
+            .iter()
+            .map(|(id, method)| (id, method.as_str()))
+    }
+
+    // a late response is then reported as `UnknownResponse`
+    pub fn forget(&mut self, id: &Id) -> Option<String> {
+        self.pending.remove(id)
+    }
+
+    pub fn cancel(&mut self, id: &Id) -> serde_json::Result<()> {
+        if self.forget(id).is_none() {
+            return Ok(());
+        }
+
+        let notification = jsonrpc::Notification {
+            jsonrpc: "2.0".to_string(),
+            method: Cancel::METHOD.to_string(),
+            params: Some(json!({ "id": id })),
+        };
+
+        self.queue(&notification)
+    }
+
+    pub fn receive_bytes(&mut self, bytes: &[u8]) {
+        self.decoder.feed(bytes);
+
+        loop {
+            match self.decoder.decode() {
+                Ok(Some(frame)) => self.receive_message(&frame),
+                Ok(None) => break,
+                Err(err) => self.events.push_back(Event::Error(err.into())),
+            }
+        }
+    }
+
+    pub fn receive_message(&mut self, msg: &[u8]) {
+        let value = match serde_json::from_slice(msg) {
+            Ok(value) => value,
+            Err(err) => {
+                let err = MalformedMessage::InvalidJson(err);
+                self.events.push_back(Event::Error(err.into()));
+                return;
+            }
+        };
+
+        let messages = match value {
+            Value::Array(batch) => batch,
+            value => vec![value],
+        };
+
+        for msg in messages {
+            let response_id = response_id(&msg).filter(|id| self.is_pending(id));
+
+            let event = match Message::from_value(msg) {
+                Ok(Message::Request(request)) => Event::Request(request),
+                Ok(Message::Notification(notification)) => Event::Notification(notification),
+                Ok(Message::Response(response)) => match self.pending.remove(&response.id) {
+                    Some(method) => Event::Response {
+                        id: response.id,
+                        method,
+                        result: response.result,
+                    },
+                    None => Event::Error(ProtocolError::UnknownResponse(response.id)),
+                },
+                // a broken response to one of our requests still answers it
+                Err(err) => match response_id {
+                    Some(id) => {
+                        self.pending.remove(&id);
+                        Event::Error(ProtocolError::MalformedResponse(id, err))
+                    }
+                    None => Event::Error(err.into()),
+                },
+            };
+
+            self.events.push_back(event);
+        }
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<String> {
+        self.outgoing.pop_front()
+    }
+
+    pub fn poll_frame(&mut self) -> Option<Vec<u8>> {
+        self.poll_transmit().map(|msg| codec::encode(&msg))
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn new_request<R: Request>(
+        &mut self,
+        params: Option<R::Params>,
+    ) -> jsonrpc::Request<R::Params> {
+        let request = jsonrpc::Request {
+            jsonrpc: "2.0".to_string(),
+            method: R::METHOD.to_string(),
+            params,
+            id: Id::Number(self.request_id_counter),
+        };
+
+        self.request_id_counter += 1;
+
+        request
+    }
+
+    fn track<P>(&mut self, request: jsonrpc::Request<P>) -> Id {
+        self.pending.insert(request.id.clone(), request.method);
+
+        request.id
+    }
+
+    fn queue(&mut self, msg: &impl Serialize) -> serde_json::Result<()> {
+        self.outgoing.push_back(serde_json::to_string(msg)?);
+
+        Ok(())
+    }
+}
+
+fn response_id(msg: &Value) -> Option<Id> {
+    if msg.get("method").is_some() {
+        return None;
+    }
+
+    serde_json::from_value(msg.get("id")?.clone()).ok()
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Frame(FrameError),
+    Malformed(MalformedMessage),
+    MalformedResponse(Id, MalformedMessage),
+    UnknownResponse(Id),
+}
+
+impl From<FrameError> for ProtocolError {
+    fn from(value: FrameError) -> Self {
+        ProtocolError::Frame(value)
+    }
+}
+
+impl From<MalformedMessage> for ProtocolError {
+    fn from(value: MalformedMessage) -> Self {
+        ProtocolError::Malformed(value)
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Frame(err) => write!(f, "malformed frame: {}", err),
+            ProtocolError::Malformed(err) => write!(f, "malformed message: {}", err),
+            ProtocolError::MalformedResponse(id, err) => {
+                write!(f, "malformed response to id {}: {}", id, err)
+            }
+            ProtocolError::UnknownResponse(id) => write!(f, "got response to unknown id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Frame(err) => Some(err),
+            ProtocolError::Malformed(err) | ProtocolError::MalformedResponse(_, err) => Some(err),
+            ProtocolError::UnknownResponse(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::notification::Initialized;
+    use lsp_types::request::{Shutdown, WorkspaceConfiguration};
+
+    use super::*;
+
+    fn drain_events(protocol: &mut Protocol) -> Vec<Event> {
+        std::iter::from_fn(|| protocol.poll_event()).collect()
+    }
+
+    #[test]
+    fn test_outgoing() {
+        let mut protocol = Protocol::new();
+
+        assert_eq!(protocol.request::<Shutdown>(None).unwrap(), Id::Number(0));
+        protocol.notify::<Initialized>(None).unwrap();
+        assert_eq!(
+            protocol
+                .request_batch::<Shutdown>(vec![None, None])
+                .unwrap(),
+            vec![Id::Number(1), Id::Number(2)]
+        );
+        protocol
+            .respond(Id::String("a".to_string()), Ok(vec![1]))
+            .unwrap();
+
+        let outgoing: Vec<_> = std::iter::from_fn(|| protocol.poll_transmit()).collect();
+        insta::assert_debug_snapshot!(outgoing, @r#"
+        [
+            "{\"jsonrpc\":\"2.0\",\"method\":\"shutdown\",\"id\":0}",
+            "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\"}",
+            "[{\"jsonrpc\":\"2.0\",\"method\":\"shutdown\",\"id\":1},{\"jsonrpc\":\"2.0\",\"method\":\"shutdown\",\"id\":2}]",
+            "{\"jsonrpc\":\"2.0\",\"result\":[1],\"id\":\"a\"}",
+        ]
+        "#);
+
+        let mut pending: Vec<_> = protocol.pending().map(|(id, _)| id.to_string()).collect();
+        pending.sort();
+        assert_eq!(pending, vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn test_poll_frame() {
+        let mut protocol = Protocol::new();
+        protocol.notify::<Initialized>(None).unwrap();
+
+        insta::assert_snapshot!(
+            String::from_utf8(protocol.poll_frame().unwrap()).unwrap(),
+            @r#"
+        Content-Length: 40
+
+        {"jsonrpc":"2.0","method":"initialized"}
+        "#
+        );
+        assert!(protocol.poll_frame().is_none());
+    }
+
+    #[test]
+    fn test_incoming() {
+        let mut protocol = Protocol::new();
+        let id = protocol.request::<Shutdown>(None).unwrap();
+        let malformed_id = protocol.request::<Shutdown>(None).unwrap();
+
+        let stream = [
+            codec::encode(r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{"items":[]},"id":"a"}"#),
+            codec::encode(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#),
+            codec::encode(r#"{"jsonrpc":"2.0","result":null,"id":7}"#),
+            codec::encode(r#"{"jsonrpc":"2.0","id":8}"#),
+            codec::encode(r#"[{"jsonrpc":"2.0","result":null,"id":0}]"#),
+            codec::encode(r#"{"jsonrpc":"2.0","result":1,"error":{"code":1,"message":""},"id":1}"#),
+        ]
+        .concat();
+
+        // feed in awkward chunks
+        for chunk in stream.chunks(7) {
+            protocol.receive_bytes(chunk);
+        }
+
+        insta::assert_debug_snapshot!(drain_events(&mut protocol), @r#"
+        [
+            Request(
+                Request {
+                    jsonrpc: "2.0",
+                    method: "workspace/configuration",
+                    params: Some(
+                        Object {
+                            "items": Array [],
+                        },
+                    ),
+                    id: String(
+                        "a",
+                    ),
+                },
+            ),
+            Notification(
+                Notification {
+                    jsonrpc: "2.0",
+                    method: "window/logMessage",
+                    params: Some(
+                        Object {
+                            "message": String("hi"),
+                            "type": Number(3),
+                        },
+                    ),
+                },
+            ),
+            Error(
+                UnknownResponse(
+                    Number(
+                        7,
+                    ),
+                ),
+            ),
+            Error(
+                Malformed(
+                    Unknown,
+                ),
+            ),
+            Response {
+                id: Number(
+                    0,
+                ),
+                method: "shutdown",
+                result: Ok(
+                    Null,
+                ),
+            },
+            Error(
+                MalformedResponse(
+                    Number(
+                        1,
+                    ),
+                    ResultAndError,
+                ),
+            ),
+        ]
+        "#);
+
+        assert!(!protocol.is_pending(&id));
+        assert!(!protocol.is_pending(&malformed_id));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut protocol = Protocol::new();
+        let id = protocol.request::<Shutdown>(None).unwrap();
+        protocol.poll_transmit();
+
+        protocol.cancel(&id).unwrap();
+        protocol.cancel(&id).unwrap();
+        assert!(!protocol.is_pending(&id));
+
+        // only cancelled once
+        let outgoing: Vec<_> = std::iter::from_fn(|| protocol.poll_transmit()).collect();
+        insta::assert_debug_snapshot!(outgoing, @r#"
+        [
+            "{\"jsonrpc\":\"2.0\",\"method\":\"$/cancelRequest\",\"params\":{\"id\":0}}",
+        ]
+        "#);
+    }
+
+    #[test]
+    fn test_server_request_round_trip() {
+        let mut protocol = Protocol::new();
+
+        protocol.receive_message(
+            br#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{"items":[{}]},"id":1}"#,
+        );
+
+        let Some(Event::Request(request)) = protocol.poll_event() else {
+            panic!("expected a request");
+        };
+
+        assert_eq!(request.method, WorkspaceConfiguration::METHOD);
+
+        protocol.respond(request.id, Ok(vec![Value::Null])).unwrap();
+
+        assert_eq!(
+            protocol.poll_transmit().unwrap(),
+            r#"{"jsonrpc":"2.0","result":[null],"id":1}"#
+        );
+    }
+}
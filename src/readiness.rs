@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use lsp_types::notification::{Notification, Progress};
+use lsp_types::request::WorkDoneProgressCreate;
+use lsp_types::{ProgressParamsValue, ProgressToken, WorkDoneProgress};
+use serde::{Deserialize, Serialize};
+
+use crate::Client;
+
+// must be created before the client is initialized, so no progress is missed
+#[derive(Clone)]
+pub struct Readiness {
+    inner: Arc<(Mutex<State>, Condvar)>,
+    settle: Duration,
+}
+
+struct State {
+    // created or began, but did not end yet
+    active: HashSet<ProgressToken>,
+    seen_progress: bool,
+    // overrides the progress when sent
+    quiescent: Option<bool>,
+    last_change: Instant,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            active: HashSet::new(),
+            seen_progress: false,
+            quiescent: None,
+            last_change: Instant::now(),
+        }
+    }
+
+    fn ready_at(&self, started: Instant, settle: Duration) -> Option<Instant> {
+        match self.quiescent {
+            Some(true) => Some(self.last_change),
+            Some(false) => None,
+            // a server that never reports progress is ready once it kept
+            // quiet for a while
+            None if !self.seen_progress => Some(started + settle),
+            None if self.active.is_empty() => Some(self.last_change + settle),
+            None => None,
+        }
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new((Mutex::new(State::new()), Condvar::new())),
+            settle: Duration::from_millis(500),
+        }
+    }
+
+    pub fn track(client: &Client) -> Self {
+        let readiness = Self::new();
+        readiness.attach(client);
+
+        readiness
+    }
+
+    // starts over, e.g. for a server that replaced a crashed one. replaces any
+    // handler registered for `window/workDoneProgress/create`
+    pub fn attach(&self, client: &Client) {
+        self.update(|state| *state = State::new());
+
+        client.on_request::<WorkDoneProgressCreate>({
+            let readiness = self.clone();
+            move |params| {
+                readiness.update(|state| {
+                    state.active.insert(params.token);
+                    state.seen_progress = true;
+                });
+                Ok(())
+            }
+        });
+
+        client.on_notification::<Progress>({
+            let readiness = self.clone();
+            move |params| {
+                let ProgressParamsValue::WorkDone(progress) = params.value;
+                readiness.update(|state| match progress {
+                    WorkDoneProgress::Begin(_) => {
+                        state.active.insert(params.token);
+                        state.seen_progress = true;
+                    }
+                    WorkDoneProgress::Report(_) => {}
+                    WorkDoneProgress::End(_) => {
+                        state.active.remove(&params.token);
+                    }
+                });
+            }
+        });
+
+        client.on_notification::<ServerStatus>({
+            let readiness = self.clone();
+            move |params| readiness.update(|state| state.quiescent = Some(params.quiescent))
+        });
+    }
+
+    pub fn with_settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    pub fn is_ready(&self) -> bool {
+        let state = self.inner.0.lock().unwrap();
+
+        state
+            .ready_at(state.last_change, self.settle)
+            .is_some_and(|ready_at| ready_at <= Instant::now())
+    }
+
+    // returns whether the server is ready
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        // too far away to matter, e.g. `Duration::MAX`
+        let deadline = started.checked_add(timeout);
+
+        let (state, changed) = &*self.inner;
+        let mut state = state.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+
+            let wake = match state.ready_at(started, self.settle) {
+                Some(ready_at) if ready_at <= now => return true,
+                Some(ready_at) => Some(deadline.map_or(ready_at, |d| ready_at.min(d))),
+                None => deadline,
+            };
+
+            state = match wake {
+                Some(_) if deadline.is_some_and(|deadline| now >= deadline) => return false,
+                Some(wake) => changed.wait_timeout(state, wake - now).unwrap().0,
+                None => changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        let mut state = self.inner.0.lock().unwrap();
+        f(&mut state);
+        state.last_change = Instant::now();
+
+        self.inner.1.notify_all();
+    }
+}
+
+// rust-analyzer's `experimental/serverStatus`
+pub enum ServerStatus {}
+
+impl Notification for ServerStatus {
+    type Params = ServerStatusParams;
+    const METHOD: &'static str = "experimental/serverStatus";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatusParams {
+    pub health: String,
+    pub quiescent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::codec;
+    use crate::Transport;
+
+    // the server sends whatever is written to the returned pipe
+    fn client() -> (Client, std::io::PipeWriter) {
+        let (client_input, server_output) = std::io::pipe().unwrap();
+        let client = Client::with_transport(Transport::streams(client_input, std::io::sink()));
+
+        (client, server_output)
+    }
+
+    fn send(server: &mut std::io::PipeWriter, method: &str, params: Value) {
+        let msg = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        server.write_all(&codec::encode(&msg.to_string())).unwrap();
+    }
+
+    fn progress(server: &mut std::io::PipeWriter, kind: &str) {
+        let value = json!({"kind": kind, "title": "Indexing"});
+        send(
+            server,
+            "$/progress",
+            json!({"token": "index", "value": value}),
+        );
+    }
+
+    #[test]
+    fn test_progress() {
+        let (client, mut server) = client();
+        let readiness = Readiness::track(&client).with_settle(Duration::from_millis(20));
+
+        progress(&mut server, "begin");
+        progress(&mut server, "report");
+        assert!(!readiness.wait(Duration::from_millis(100)));
+
+        progress(&mut server, "end");
+        assert!(readiness.wait(Duration::from_secs(5)));
+        assert!(readiness.is_ready());
+    }
+
+    #[test]
+    fn test_no_progress() {
+        let (client, _server) = client();
+        let readiness = Readiness::track(&client).with_settle(Duration::from_millis(20));
+
+        assert!(readiness.wait(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_attach() {
+        let (client, mut server) = client();
+        let readiness = Readiness::track(&client).with_settle(Duration::from_millis(20));
+        progress(&mut server, "begin");
+        assert!(!readiness.wait(Duration::from_millis(100)));
+
+        // a restarted server starts over
+        let (client, mut server) = self::client();
+        readiness.attach(&client);
+        assert!(readiness.wait(Duration::from_secs(5)));
+
+        progress(&mut server, "begin");
+        assert!(!readiness.wait(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_server_status() {
+        let (client, mut server) = client();
+        let readiness = Readiness::track(&client).with_settle(Duration::from_millis(20));
+
+        // the status wins over the missing progress
+        let status = json!({"health": "ok", "quiescent": false});
+        send(&mut server, "experimental/serverStatus", status);
+        assert!(!readiness.wait(Duration::from_millis(100)));
+
+        let status = json!({"health": "ok", "quiescent": true});
+        send(&mut server, "experimental/serverStatus", status);
+        assert!(readiness.wait(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::transport::{MessageReader, MessageWriter, Transport};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Entry {
+    Send(Value),
+    Recv(Value),
+}
+
+impl Transport {
+    // one JSON object per line, for `Replay`
+    pub fn record(self, out: impl Write + Send + 'static) -> Self {
+        let out: Arc<Mutex<Box<dyn Write + Send>>> = Arc::new(Mutex::new(Box::new(out)));
+
+        Self {
+            reader: Box::new(RecordingReader {
+                reader: self.reader,
+                out: out.clone(),
+            }),
+            writer: Box::new(RecordingWriter {
+                writer: self.writer,
+                out,
+            }),
+            child: self.child,
+        }
+    }
+}
+
+fn record(out: &Mutex<Box<dyn Write + Send>>, entry: &Entry) -> Result<()> {
+    let mut out = out.lock().unwrap();
+
+    serde_json::to_writer(&mut *out, entry)?;
+    writeln!(out)?;
+    out.flush().context("writing recording")
+}
+
+// the process id of the client changes with every run
+fn same(expected: &Value, msg: &Value) -> bool {
+    let without_process_id = |msg: &Value| {
+        let mut msg = msg.clone();
+        if msg["method"] == "initialize" {
+            if let Some(params) = msg["params"].as_object_mut() {
+                params.remove("processId");
+            }
+        }
+        msg
+    };
+
+    expected == msg || without_process_id(expected) == without_process_id(msg)
+}
+
+fn parse(msg: &[u8]) -> Value {
+    // keep what can't be parsed, so it can still be looked at
+    serde_json::from_slice(msg)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(msg).into_owned()))
+}
+
+struct RecordingReader {
+    reader: Box<dyn MessageReader>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl MessageReader for RecordingReader {
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let msg = self.reader.read_message()?;
+
+        if let Some(msg) = &msg {
+            record(&self.out, &Entry::Recv(parse(msg)))?;
+        }
+
+        Ok(msg)
+    }
+}
+
+struct RecordingWriter {
+    writer: Box<dyn MessageWriter>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl MessageWriter for RecordingWriter {
+    fn write_message(&mut self, msg: &str) -> Result<()> {
+        record(&self.out, &Entry::Send(parse(msg.as_bytes())))?;
+
+        self.writer.write_message(msg)
+    }
+}
+
+// received messages are played back once the client sent every message
+// recorded before them, and sent messages must match the recording
+#[derive(Clone)]
+pub struct Replay {
+    inner: Arc<(Mutex<ReplayState>, Condvar)>,
+}
+
+struct ReplayState {
+    entries: VecDeque<Entry>,
+    mismatch: Option<String>,
+}
+
+impl Replay {
+    pub fn new(recording: impl BufRead) -> Result<Self> {
+        let entries = recording
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|(i, line)| {
+                serde_json::from_str(&line?).with_context(|| format!("parsing line {}", i + 1))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            inner: Arc::new((
+                Mutex::new(ReplayState {
+                    entries,
+                    mismatch: None,
+                }),
+                Condvar::new(),
+            )),
+        })
+    }
+
+    pub fn file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).context("opening recording")?;
+
+        Self::new(BufReader::new(file))
+    }
+
+    pub fn transport(&self) -> Transport {
+        Transport::new(
+            ReplayReader {
+                replay: self.clone(),
+            },
+            ReplayWriter {
+                replay: self.clone(),
+            },
+        )
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        let state = self.inner.0.lock().unwrap();
+
+        if let Some(mismatch) = &state.mismatch {
+            anyhow::bail!("{}", mismatch);
+        }
+
+        if let Some(Entry::Send(expected)) =
+            state.entries.iter().find(|e| matches!(e, Entry::Send(_)))
+        {
+            anyhow::bail!("client never sent {}", expected);
+        }
+
+        Ok(())
+    }
+}
+
+struct ReplayReader {
+    replay: Replay,
+}
+
+impl MessageReader for ReplayReader {
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let (state, changed) = &*self.replay.inner;
+        let mut state = state.lock().unwrap();
+
+        loop {
+            if state.mismatch.is_some() {
+                return Ok(None);
+            }
+
+            match state.entries.front() {
+                Some(Entry::Recv(_)) => {
+                    let Some(Entry::Recv(msg)) = state.entries.pop_front() else {
+                        unreachable!()
+                    };
+
+                    return Ok(Some(serde_json::to_vec(&msg)?));
+                }
+                // wait for the client to catch up
+                Some(Entry::Send(_)) => state = changed.wait(state).unwrap(),
+                // the recording ended, so does the connection
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+struct ReplayWriter {
+    replay: Replay,
+}
+
+impl MessageWriter for ReplayWriter {
+    fn write_message(&mut self, msg: &str) -> Result<()> {
+        let (state, changed) = &*self.replay.inner;
+        let mut state = state.lock().unwrap();
+
+        if let Some(mismatch) = &state.mismatch {
+            anyhow::bail!("{}", mismatch);
+        }
+
+        let msg = parse(msg.as_bytes());
+
+        // the server may still have messages to play back first, but the
+        // client can't know that
+        let next = state
+            .entries
+            .iter()
+            .position(|entry| matches!(entry, Entry::Send(_)));
+
+        let mismatch = match next.map(|i| (i, &state.entries[i])) {
+            Some((i, Entry::Send(expected))) if same(expected, &msg) => {
+                state.entries.remove(i);
+                changed.notify_all();
+                return Ok(());
+            }
+            Some((_, Entry::Send(expected))) => format!(
+                "client sent {}, but the recording expected {}",
+                msg, expected
+            ),
+            _ => format!("client sent {} after the recording ended", msg),
+        };
+
+        // stop playing back, the recording is no good anymore
+        state.mismatch = Some(mismatch.clone());
+        changed.notify_all();
+
+        anyhow::bail!("{}", mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::notification::Initialized;
+    use lsp_types::request::Shutdown;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{frame, stand_in_server, Shared};
+    use crate::Client;
+
+    #[test]
+    fn test_record_then_replay() {
+        // log, then answer every request
+        let transport = stand_in_server(|msg| {
+            let Some(id) = msg.get("id") else {
+                return Some(vec![]);
+            };
+
+            let log = json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {"type": 3, "message": "hi"}});
+            let response = json!({"jsonrpc": "2.0", "result": null, "id": id});
+            Some([frame(log), frame(response)].concat())
+        });
+
+        let recording = Shared::default();
+        let transport = transport.record(recording.clone());
+        let client = Client::with_transport(transport);
+
+        client.notify::<Initialized>(None).unwrap();
+        client.request::<Shutdown>(None).unwrap();
+        drop(client);
+
+        let recording = recording.bytes();
+        insta::assert_snapshot!(String::from_utf8(recording.clone()).unwrap(), @r#"
+        {"send":{"jsonrpc":"2.0","method":"initialized"}}
+        {"send":{"id":0,"jsonrpc":"2.0","method":"shutdown"}}
+        {"recv":{"jsonrpc":"2.0","method":"window/logMessage","params":{"message":"hi","type":3}}}
+        {"recv":{"id":0,"jsonrpc":"2.0","result":null}}
+        "#);
+
+        // the same session works without the server
+        let replay = Replay::new(recording.as_slice()).unwrap();
+        let client = Client::with_transport(replay.transport());
+        let logs = client.subscribe::<lsp_types::notification::LogMessage>();
+
+        client.notify::<Initialized>(None).unwrap();
+        client.request::<Shutdown>(None).unwrap();
+
+        assert_eq!(logs.recv().unwrap().message, "hi");
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn test_replay_mismatch() {
+        let recording = [
+            r#"{"send":{"jsonrpc":"2.0","method":"shutdown","id":0}}"#,
+            r#"{"recv":{"jsonrpc":"2.0","result":null,"id":0}}"#,
+        ]
+        .join("\n");
+
+        let replay = Replay::new(recording.as_bytes()).unwrap();
+        let client = Client::with_transport(replay.transport());
+
+        let err = client.notify::<Initialized>(None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"client sent {"jsonrpc":"2.0","method":"initialized"}, but the recording expected {"id":0,"jsonrpc":"2.0","method":"shutdown"}"#
+        );
+        assert_eq!(replay.finish().unwrap_err().to_string(), err.to_string());
+    }
+
+    #[test]
+    fn test_replay_unfinished() {
+        let recording = r#"{"send":{"jsonrpc":"2.0","method":"initialized"}}"#;
+        let replay = Replay::new(recording.as_bytes()).unwrap();
+
+        assert_eq!(
+            replay.finish().unwrap_err().to_string(),
+            r#"client never sent {"jsonrpc":"2.0","method":"initialized"}"#
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Result;
+use lsp_types::{InitializeParams, ServerCapabilities, Uri};
+
+use crate::{Client, InitializeBuilder, Transport};
+
+type Connect = Box<dyn Fn() -> Result<Transport> + Send + Sync>;
+type Setup = Box<dyn Fn(&Client) + Send + Sync>;
+type RestartLog = Box<dyn Fn(&Restart) + Send + Sync>;
+
+// a restarted server is initialized like the first one, and gets every
+// document that is still open. work done through `run` is then retried on it
+#[derive(Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connect: Connect,
+    setup: Setup,
+    restart_log: RestartLog,
+    retries: usize,
+    // with the number of restarts it took to get it
+    current: RwLock<(u64, Client)>,
+    // held while restarting, so a crash only restarts the server once
+    restarting: Mutex<()>,
+    // set by `shutdown`, a server that is gone then stays gone
+    stopped: AtomicBool,
+    params: Mutex<Option<InitializeParams>>,
+    documents: Mutex<HashMap<Uri, String>>,
+}
+
+impl Supervisor {
+    // `connect` is called again for every restart
+    pub fn new(connect: impl Fn() -> Result<Transport> + Send + Sync + 'static) -> Result<Self> {
+        let client = Client::with_transport(connect()?);
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                connect: Box::new(connect),
+                setup: Box::new(|_| {}),
+                restart_log: Box::new(|_| {}),
+                retries: 3,
+                current: RwLock::new((0, client)),
+                restarting: Mutex::new(()),
+                stopped: AtomicBool::new(false),
+                params: Mutex::new(None),
+                documents: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.inner_mut().retries = retries;
+        self
+    }
+
+    // called with every client before it is initialized
+    pub fn with_setup(mut self, setup: impl Fn(&Client) + Send + Sync + 'static) -> Self {
+        setup(&self.client());
+        self.inner_mut().setup = Box::new(setup);
+        self
+    }
+
+    pub fn with_restart_log(mut self, log: impl Fn(&Restart) + Send + Sync + 'static) -> Self {
+        self.inner_mut().restart_log = Box::new(log);
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("supervisor configured after it was cloned")
+    }
+
+    pub fn client(&self) -> Client {
+        self.inner.current.read().unwrap().1.clone()
+    }
+
+    pub fn initialize(&self, root: Uri) -> Result<ServerCapabilities> {
+        self.initialize_with(InitializeBuilder::new(root).build())
+    }
+
+    pub fn initialize_with(&self, params: InitializeParams) -> Result<ServerCapabilities> {
+        *self.inner.params.lock().unwrap() = Some(params.clone());
+
+        self.client().initialize_with(params)
+    }
+
+    pub fn open(&self, uri: &Uri, text: &str) -> Result<()> {
+        self.inner
+            .documents
+            .lock()
+            .unwrap()
+            .entry(uri.clone())
+            .or_insert_with(|| text.to_string());
+
+        // a restarted server got the document already, so opening it again
+        // is a no-op
+        self.run(|client| client.open(uri, text))
+    }
+
+    pub fn change(&self, uri: &Uri, text: &str) -> Result<()> {
+        if let Some(document) = self.inner.documents.lock().unwrap().get_mut(uri) {
+            *document = text.to_string();
+        }
+
+        self.run(|client| client.change(uri, text))
+    }
+
+    pub fn close(&self, uri: &Uri) -> Result<()> {
+        self.inner.documents.lock().unwrap().remove(uri);
+
+        self.run(|client| client.close(uri))
+    }
+
+    pub fn run<T>(&self, mut f: impl FnMut(&Client) -> Result<T>) -> Result<T> {
+        let mut retries = 0;
+
+        loop {
+            if self.inner.stopped.load(Ordering::SeqCst) {
+                anyhow::bail!("server was shut down");
+            }
+
+            let (restarts, client) = self.inner.current.read().unwrap().clone();
+
+            match f(&client) {
+                Err(err) if retries < self.inner.retries && crashed(&client) => {
+                    retries += 1;
+                    self.inner.restart(restarts, &err)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn shutdown(&self) -> Result<Option<ExitStatus>> {
+        self.inner.stopped.store(true, Ordering::SeqCst);
+
+        // a restart already underway finishes first, its server is shut down
+        let _restarting = self.inner.restarting.lock().unwrap();
+        self.client().shutdown()
+    }
+}
+
+impl Inner {
+    fn restart(&self, restarts: u64, err: &anyhow::Error) -> Result<()> {
+        let _restarting = self.restarting.lock().unwrap();
+        if self.stopped.load(Ordering::SeqCst) {
+            anyhow::bail!("server was shut down");
+        }
+
+        let crashed = {
+            let current = self.current.read().unwrap();
+            if current.0 != restarts {
+                return Ok(());
+            }
+
+            current.1.clone()
+        };
+
+        // reap the crashed server, shutting it down would lose its status
+        let status = crashed
+            .with_child(|child| match child.try_wait() {
+                Ok(Some(status)) => Some(status),
+                // alive, but not talking to us anymore
+                _ => {
+                    let _ = child.kill();
+                    child.wait().ok()
+                }
+            })
+            .flatten();
+        let _ = crashed.shutdown();
+
+        let client = Client::with_transport((self.connect)()?);
+        (self.setup)(&client);
+
+        if let Some(params) = self.params.lock().unwrap().clone() {
+            client.initialize_with(params)?;
+        }
+
+        for (uri, text) in self.documents.lock().unwrap().iter() {
+            client.open(uri, text)?;
+        }
+
+        *self.current.write().unwrap() = (restarts + 1, client);
+
+        (self.restart_log)(&Restart {
+            restarts: restarts + 1,
+            status,
+            reason: format!("{:#}", err),
+        });
+
+        Ok(())
+    }
+}
+
+fn crashed(client: &Client) -> bool {
+    let exited = client.with_child(|child| matches!(child.try_wait(), Ok(Some(_))));
+
+    client.is_closed() || exited == Some(true)
+}
+
+#[derive(Debug)]
+pub struct Restart {
+    pub restarts: u64,
+    pub status: Option<ExitStatus>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Restart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server restarted (#{}) after {}",
+            self.restarts, self.reason
+        )?;
+
+        match self.status {
+            Some(status) => write!(f, ", it exited with {}", status),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::mpsc;
+
+    use lsp_types::request::Shutdown;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_util::{frame, stand_in_server};
+    use crate::MockServer;
+
+    // the server sends the methods it got to `tx` and answers every request,
+    // until it gets a `shutdown` while `crash` is set
+    fn connect(tx: mpsc::Sender<Value>, crash: bool) -> Result<Transport> {
+        Ok(stand_in_server(move |msg| {
+            let _ = tx.send(msg["method"].clone());
+
+            if crash && msg["method"] == "shutdown" {
+                return None;
+            }
+
+            if msg.get("id").is_none() {
+                return Some(vec![]);
+            }
+
+            let result = match msg["method"].as_str() {
+                Some("initialize") => json!({"capabilities": {}}),
+                _ => Value::Null,
+            };
+            Some(frame(
+                json!({"jsonrpc": "2.0", "result": result, "id": msg["id"]}),
+            ))
+        }))
+    }
+
+    #[test]
+    fn test_restart() {
+        let (tx, rx) = mpsc::channel();
+
+        // only the first server crashes
+        let connects = Mutex::new(0);
+        let supervisor = Supervisor::new(move || {
+            let mut connects = connects.lock().unwrap();
+            *connects += 1;
+            connect(tx.clone(), *connects == 1)
+        })
+        .unwrap();
+
+        let (log_tx, log_rx) = mpsc::channel();
+        let supervisor = supervisor.with_restart_log(move |restart| {
+            log_tx.send(restart.restarts).unwrap();
+        });
+
+        let uri = Uri::from_str("file:///main.rs").unwrap();
+        supervisor
+            .initialize(Uri::from_str("file:///").unwrap())
+            .unwrap();
+        supervisor.open(&uri, "fn main() {}").unwrap();
+
+        supervisor
+            .run(|client| client.request::<Shutdown>(None))
+            .unwrap();
+
+        assert_eq!(log_rx.try_iter().collect::<Vec<_>>(), vec![1]);
+
+        let methods: Vec<_> = rx.try_iter().collect();
+        insta::assert_debug_snapshot!(methods, @r#"
+        [
+            String("initialize"),
+            String("initialized"),
+            String("textDocument/didOpen"),
+            String("shutdown"),
+            String("initialize"),
+            String("initialized"),
+            String("textDocument/didOpen"),
+            String("shutdown"),
+        ]
+        "#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restart_status() {
+        // the first server exits right away
+        let connects = Mutex::new(0);
+        let supervisor = Supervisor::new(move || {
+            let mut connects = connects.lock().unwrap();
+            *connects += 1;
+            match *connects {
+                1 => Transport::stdio(std::process::Command::new("sh").args(["-c", "exit 3"])),
+                _ => Ok(MockServer::new().start().0),
+            }
+        })
+        .unwrap();
+
+        let (log_tx, log_rx) = mpsc::channel();
+        let supervisor = supervisor.with_restart_log(move |restart| {
+            log_tx
+                .send(restart.status.and_then(|status| status.code()))
+                .unwrap();
+        });
+
+        while !supervisor.client().is_closed() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        supervisor
+            .run(|client| client.request::<Shutdown>(None))
+            .unwrap();
+        assert_eq!(log_rx.try_iter().collect::<Vec<_>>(), vec![Some(3)]);
+    }
+
+    #[test]
+    fn test_no_restart_after_shutdown() {
+        let (tx, _rx) = mpsc::channel();
+        let connects = Arc::new(Mutex::new(0));
+        let supervisor = Supervisor::new({
+            let connects = connects.clone();
+            move || {
+                *connects.lock().unwrap() += 1;
+                connect(tx.clone(), false)
+            }
+        })
+        .unwrap();
+
+        supervisor.shutdown().unwrap();
+
+        let err = supervisor
+            .run(|client| client.request::<Shutdown>(None))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "server was shut down");
+        assert_eq!(*connects.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_retries_are_bounded() {
+        let (tx, _rx) = mpsc::channel();
+        let supervisor = Supervisor::new(move || connect(tx.clone(), true))
+            .unwrap()
+            .with_retries(2);
+
+        let (log_tx, log_rx) = mpsc::channel();
+        let supervisor = supervisor.with_restart_log(move |restart| {
+            log_tx.send(restart.restarts).unwrap();
+        });
+
+        let err = supervisor
+            .run(|client| client.request::<Shutdown>(None))
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "server closed the connection");
+        assert_eq!(log_rx.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
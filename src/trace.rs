@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use lsp_types::notification::{LogTrace, Notification};
+use lsp_types::{LogTraceParams, TraceValue};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::jsonrpc::{Id, Message};
+
+// the format editors write to their output panel, `$/logTrace` notifications
+// are written as they are
+pub struct TraceLog {
+    out: Arc<Mutex<dyn Write + Send>>,
+    level: TraceValue,
+    // requests waiting for their response
+    sent: HashMap<Id, (String, Instant)>,
+    received: HashMap<Id, (String, Instant)>,
+}
+
+impl TraceLog {
+    // params and results are only written at `TraceValue::Verbose`
+    pub fn new(out: impl Write + Send + 'static, level: TraceValue) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(out)),
+            level,
+            sent: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    pub fn file(path: impl AsRef<Path>, level: TraceValue) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("opening trace file")?;
+
+        Ok(Self::new(BufWriter::new(file), level))
+    }
+
+    pub fn level(&self) -> TraceValue {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: TraceValue) {
+        self.level = level;
+    }
+
+    // the entries are written with `Entries::write`, so the caller can
+    // release its locks first
+    pub(crate) fn outgoing(&mut self, msg: &str) -> Entries {
+        self.trace(
+            msg.as_bytes(),
+            Direction::Sending,
+            Instant::now(),
+            SystemTime::now(),
+        )
+    }
+
+    pub(crate) fn incoming(&mut self, msg: &[u8]) -> Entries {
+        self.trace(msg, Direction::Received, Instant::now(), SystemTime::now())
+    }
+
+    fn trace(
+        &mut self,
+        msg: &[u8],
+        direction: Direction,
+        now: Instant,
+        time: SystemTime,
+    ) -> Entries {
+        let mut entries = Entries {
+            out: self.out.clone(),
+            text: String::new(),
+        };
+
+        if self.level == TraceValue::Off {
+            return entries;
+        }
+
+        // malformed messages are reported by the protocol
+        let messages = match serde_json::from_slice(msg) {
+            Ok(Value::Array(batch)) => batch,
+            Ok(value) => vec![value],
+            Err(_) => return entries,
+        };
+
+        for msg in messages
+            .into_iter()
+            .filter_map(|msg| Message::from_value(msg).ok())
+        {
+            let (title, data) = self.describe(msg, direction, now);
+            entries.text += &format!("[Trace - {}] {}\n", time_of_day(time), title);
+
+            if let (TraceValue::Verbose, Some(data)) = (self.level, data) {
+                entries.text += &format!("{}\n\n\n", data);
+            }
+        }
+
+        entries
+    }
+
+    fn describe(
+        &mut self,
+        msg: Message,
+        direction: Direction,
+        now: Instant,
+    ) -> (String, Option<String>) {
+        let (requests, responses) = match direction {
+            Direction::Sending => (&mut self.sent, &mut self.received),
+            Direction::Received => (&mut self.received, &mut self.sent),
+        };
+
+        match msg {
+            Message::Request(request) => {
+                let title = format!(
+                    "{} request '{} - ({})'.",
+                    direction,
+                    request.method,
+                    id(&request.id)
+                );
+                requests.insert(request.id, (request.method, now));
+
+                (title, Some(params(request.params.as_ref())))
+            }
+            Message::Notification(notification) if notification.method == LogTrace::METHOD => {
+                let params = notification
+                    .params
+                    .and_then(|params| serde_json::from_value::<LogTraceParams>(params).ok());
+
+                match params {
+                    Some(params) => (params.message, params.verbose),
+                    None => ("Received invalid $/logTrace.".to_string(), None),
+                }
+            }
+            Message::Notification(notification) => (
+                format!("{} notification '{}'.", direction, notification.method),
+                Some(params(notification.params.as_ref())),
+            ),
+            Message::Response(response) => {
+                let Some((method, since)) = responses.remove(&response.id) else {
+                    return (
+                        format!(
+                            "{} response {} without active request.",
+                            direction,
+                            id(&response.id)
+                        ),
+                        None,
+                    );
+                };
+
+                let took = (now - since).as_millis();
+                let mut title = match direction {
+                    Direction::Sending => format!(
+                        "Sending response '{} - ({})'. Processing request took {}ms",
+                        method,
+                        id(&response.id),
+                        took
+                    ),
+                    Direction::Received => format!(
+                        "Received response '{} - ({})' in {}ms.",
+                        method,
+                        id(&response.id),
+                        took
+                    ),
+                };
+
+                let data = match response.result {
+                    Ok(Value::Null) => "No result returned.".to_string(),
+                    Ok(result) => format!("Result: {}", pretty(&result)),
+                    Err(err) => {
+                        title += &format!(
+                            " Request failed: {} ({}).",
+                            err.message,
+                            i64::from(err.code)
+                        );
+
+                        match err.data {
+                            Some(data) => format!("Error data: {}", pretty(&data)),
+                            None => "No result returned.".to_string(),
+                        }
+                    }
+                };
+
+                (title, Some(data))
+            }
+        }
+    }
+}
+
+pub(crate) struct Entries {
+    out: Arc<Mutex<dyn Write + Send>>,
+    text: String,
+}
+
+impl Entries {
+    pub(crate) fn write(self) {
+        if self.text.is_empty() {
+            return;
+        }
+
+        let mut out = self.out.lock().unwrap();
+        // a broken trace file shouldn't break the client
+        let _ = out
+            .write_all(self.text.as_bytes())
+            .and_then(|()| out.flush());
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Sending,
+    Received,
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Sending => write!(f, "Sending"),
+            Direction::Received => write!(f, "Received"),
+        }
+    }
+}
+
+// strings without quotes, like editors show them
+fn id(id: &Id) -> String {
+    match id {
+        Id::String(id) => id.clone(),
+        id => id.to_string(),
+    }
+}
+
+fn params(params: Option<&Value>) -> String {
+    match params {
+        Some(params) => format!("Params: {}", pretty(params)),
+        None => "No parameters provided.".to_string(),
+    }
+}
+
+// indented with 4 spaces, like editors do
+fn pretty(value: &impl Serialize) -> String {
+    let mut out = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+
+    match value.serialize(&mut serializer) {
+        Ok(()) => String::from_utf8(out).unwrap_or_default(),
+        Err(_) => String::new(),
+    }
+}
+
+// the UTC time of day, as `9:41:07 AM`
+fn time_of_day(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        % (24 * 60 * 60);
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    let period = if hours < 12 { "AM" } else { "PM" };
+    let hours = match hours % 12 {
+        0 => 12,
+        hours => hours,
+    };
+
+    format!("{}:{:02}:{:02} {}", hours, minutes, seconds, period)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::Shared;
+
+    fn trace(level: TraceValue, messages: &[(Direction, &str)]) -> String {
+        let out = Shared::default();
+        let mut log = TraceLog::new(out.clone(), level);
+
+        let start = Instant::now();
+        let time = UNIX_EPOCH + Duration::from_secs(13 * 3600 + 5 * 60 + 9);
+
+        for (i, (direction, msg)) in messages.iter().enumerate() {
+            let now = start + Duration::from_millis(10 * i as u64);
+            log.trace(msg.as_bytes(), *direction, now, time).write();
+        }
+
+        String::from_utf8(out.bytes()).unwrap()
+    }
+
+    const SESSION: &[(Direction, &str)] = &[
+        (
+            Direction::Sending,
+            r#"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{}},"id":0}"#,
+        ),
+        (
+            Direction::Received,
+            r#"{"jsonrpc":"2.0","method":"workspace/configuration","params":{"items":[]},"id":"a"}"#,
+        ),
+        (
+            Direction::Sending,
+            r#"{"jsonrpc":"2.0","result":[],"id":"a"}"#,
+        ),
+        (
+            Direction::Received,
+            r#"{"jsonrpc":"2.0","result":{"capabilities":{}},"id":0}"#,
+        ),
+        (
+            Direction::Sending,
+            r#"{"jsonrpc":"2.0","method":"initialized"}"#,
+        ),
+        (
+            Direction::Received,
+            r#"{"jsonrpc":"2.0","method":"$/logTrace","params":{"message":"indexing","verbose":"3 crates"}}"#,
+        ),
+        (
+            Direction::Sending,
+            r#"[{"jsonrpc":"2.0","method":"shutdown","id":1}]"#,
+        ),
+        (
+            Direction::Received,
+            r#"{"jsonrpc":"2.0","error":{"code":-32600,"message":"nope"},"id":1}"#,
+        ),
+        (
+            Direction::Received,
+            r#"{"jsonrpc":"2.0","result":null,"id":7}"#,
+        ),
+    ];
+
+    #[test]
+    fn test_messages() {
+        insta::assert_snapshot!(trace(TraceValue::Messages, SESSION), @r"
+        [Trace - 1:05:09 PM] Sending request 'initialize - (0)'.
+        [Trace - 1:05:09 PM] Received request 'workspace/configuration - (a)'.
+        [Trace - 1:05:09 PM] Sending response 'workspace/configuration - (a)'. Processing request took 10ms
+        [Trace - 1:05:09 PM] Received response 'initialize - (0)' in 30ms.
+        [Trace - 1:05:09 PM] Sending notification 'initialized'.
+        [Trace - 1:05:09 PM] indexing
+        [Trace - 1:05:09 PM] Sending request 'shutdown - (1)'.
+        [Trace - 1:05:09 PM] Received response 'shutdown - (1)' in 10ms. Request failed: nope (-32600).
+        [Trace - 1:05:09 PM] Received response 7 without active request.
+        ");
+    }
+
+    #[test]
+    fn test_verbose() {
+        insta::assert_snapshot!(trace(TraceValue::Verbose, &SESSION[..6]), @r#"
+        [Trace - 1:05:09 PM] Sending request 'initialize - (0)'.
+        Params: {
+            "capabilities": {}
+        }
+
+
+        [Trace - 1:05:09 PM] Received request 'workspace/configuration - (a)'.
+        Params: {
+            "items": []
+        }
+
+
+        [Trace - 1:05:09 PM] Sending response 'workspace/configuration - (a)'. Processing request took 10ms
+        Result: []
+
+
+        [Trace - 1:05:09 PM] Received response 'initialize - (0)' in 30ms.
+        Result: {
+            "capabilities": {}
+        }
+
+
+        [Trace - 1:05:09 PM] Sending notification 'initialized'.
+        No parameters provided.
+
+
+        [Trace - 1:05:09 PM] indexing
+        3 crates
+        "#);
+    }
+
+    #[test]
+    fn test_off() {
+        assert_eq!(trace(TraceValue::Off, SESSION), "");
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        let at = |secs| time_of_day(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(at(0), "12:00:00 AM");
+        assert_eq!(at(12 * 3600 + 1), "12:00:01 PM");
+        assert_eq!(at(23 * 3600 + 59 * 60 + 59), "11:59:59 PM");
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::WebSocket;
+
+use crate::codec::{self, FrameReader};
+
+pub trait MessageReader: Send {
+    // `None` once the connection is closed
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+pub trait MessageWriter: Send {
+    fn write_message(&mut self, msg: &str) -> Result<()>;
+}
+
+pub struct Transport {
+    pub(crate) reader: Box<dyn MessageReader>,
+    pub(crate) writer: Box<dyn MessageWriter>,
+    pub(crate) child: Option<Child>,
+}
+
+impl Transport {
+    pub fn new(reader: impl MessageReader + 'static, writer: impl MessageWriter + 'static) -> Self {
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            child: None,
+        }
+    }
+
+    pub fn streams(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Self {
+        Self::new(FramedReader::new(reader), FramedWriter::new(writer))
+    }
+
+    pub fn stdio(command: &mut Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("spawning server")?;
+
+        let input = BufReader::new(child.stdout.take().context("taking child stdout")?);
+        let output = child.stdin.take().context("taking child stdin")?;
+
+        Ok(Self::streams(input, output).with_child(child))
+    }
+
+    pub fn tcp(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).context("connecting to server")?;
+
+        Self::tcp_stream(stream)
+    }
+
+    // e.g. for a server started with `--socket=PORT`
+    pub fn tcp_accept(listener: &TcpListener) -> Result<Self> {
+        let (stream, _) = listener.accept().context("accepting server connection")?;
+
+        Self::tcp_stream(stream)
+    }
+
+    fn tcp_stream(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        let input = stream.try_clone().context("cloning tcp stream")?;
+
+        Ok(Self::streams(input, stream))
+    }
+
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let stream =
+            std::os::unix::net::UnixStream::connect(path).context("connecting to server")?;
+        let input = stream.try_clone().context("cloning unix stream")?;
+
+        Ok(Self::streams(input, stream))
+    }
+
+    #[cfg(unix)]
+    pub fn unix_accept(listener: &std::os::unix::net::UnixListener) -> Result<Self> {
+        let (stream, _) = listener.accept().context("accepting server connection")?;
+        let input = stream.try_clone().context("cloning unix stream")?;
+
+        Ok(Self::streams(input, stream))
+    }
+
+    // one message per WebSocket message, without `Content-Length` framing
+    pub fn websocket(url: &str) -> Result<Self> {
+        let request = url.into_client_request().context("parsing websocket url")?;
+        if request.uri().scheme_str() != Some("ws") {
+            anyhow::bail!("only ws:// urls are supported, not {:?}", url);
+        }
+
+        let host = request.uri().host().context("websocket url has no host")?;
+        let port = request.uri().port_u16().unwrap_or(80);
+
+        let stream = TcpStream::connect((host, port)).context("connecting to server")?;
+        stream.set_nodelay(true)?;
+
+        let (socket, _) = tungstenite::client(request, stream)
+            .map_err(|err| anyhow::anyhow!("websocket handshake failed: {}", err))?;
+
+        Self::websocket_stream(socket)
+    }
+
+    pub fn websocket_stream(socket: WebSocket<TcpStream>) -> Result<Self> {
+        // the socket is shared by both halves, so reads must not hold the
+        // lock forever
+        let stream = socket.get_ref().try_clone()?;
+        stream.set_read_timeout(Some(WEBSOCKET_READ_TIMEOUT))?;
+
+        let socket = Arc::new(Mutex::new(socket));
+
+        Ok(Self::new(
+            WebSocketReader {
+                socket: socket.clone(),
+                stream,
+            },
+            WebSocketWriter { socket },
+        ))
+    }
+
+    pub fn with_child(mut self, child: Child) -> Self {
+        self.child = Some(child);
+        self
+    }
+
+    pub fn child_mut(&mut self) -> Option<&mut Child> {
+        self.child.as_mut()
+    }
+}
+
+pub struct FramedReader<R> {
+    reader: FrameReader<R>,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: FrameReader::new(reader),
+        }
+    }
+}
+
+impl<R: Read + Send> MessageReader for FramedReader<R> {
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.reader.read_frame()?)
+    }
+}
+
+pub struct FramedWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Send> MessageWriter for FramedWriter<W> {
+    fn write_message(&mut self, msg: &str) -> Result<()> {
+        self.writer
+            .write_all(&codec::encode(msg))
+            .context("writing msg to output")?;
+
+        self.writer.flush().context("flushing output")
+    }
+}
+
+const WEBSOCKET_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct WebSocketReader {
+    socket: Arc<Mutex<WebSocket<TcpStream>>>,
+    // for waiting on the socket without the lock
+    stream: TcpStream,
+}
+
+impl MessageReader for WebSocketReader {
+    fn read_message(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let msg = self.socket.lock().unwrap().read();
+
+            match msg {
+                Ok(tungstenite::Message::Text(text)) => return Ok(Some(text.as_bytes().to_vec())),
+                Ok(tungstenite::Message::Binary(bytes)) => return Ok(Some(bytes.to_vec())),
+                Ok(tungstenite::Message::Close(_)) => return Ok(None),
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(None)
+                }
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    // wait for more without the lock, so the writer can have it
+                    let _ = self.stream.peek(&mut [0]);
+                }
+                Err(err) => return Err(err).context("reading websocket message"),
+            }
+        }
+    }
+}
+
+pub struct WebSocketWriter {
+    socket: Arc<Mutex<WebSocket<TcpStream>>>,
+}
+
+impl MessageWriter for WebSocketWriter {
+    fn write_message(&mut self, msg: &str) -> Result<()> {
+        self.socket
+            .lock()
+            .unwrap()
+            .send(tungstenite::Message::text(msg))
+            .context("writing websocket message")
+    }
+}
+
+impl Drop for WebSocketWriter {
+    fn drop(&mut self) {
+        // the reader shares the socket, so it has to be closed explicitly
+        let _ = self.socket.lock().unwrap().close(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use lsp_types::request::Shutdown;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_util::{frame, serve};
+    use crate::Client;
+
+    // answers every request with `null`, and passes it on
+    fn stand_in_server(
+        input: impl Read + Send + 'static,
+        output: impl Write + Send + 'static,
+    ) -> mpsc::Receiver<Value> {
+        let (tx, rx) = mpsc::channel();
+
+        serve(input, output, move |request| {
+            let response = frame(json!({"jsonrpc": "2.0", "result": null, "id": request["id"]}));
+            let _ = tx.send(request);
+            Some(response)
+        });
+
+        rx
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stand_in_server(stream.try_clone().unwrap(), stream)
+        });
+
+        let client = Client::with_transport(Transport::tcp(addr).unwrap());
+        client.request::<Shutdown>(None).unwrap();
+
+        assert_eq!(
+            server.join().unwrap().recv().unwrap(),
+            json!({"jsonrpc": "2.0", "method": "shutdown", "id": 0})
+        );
+    }
+
+    #[test]
+    fn test_tcp_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the server connects to the client
+        let server = std::thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stand_in_server(stream.try_clone().unwrap(), stream)
+        });
+
+        let client = Client::with_transport(Transport::tcp_accept(&listener).unwrap());
+        client.request::<Shutdown>(None).unwrap();
+
+        assert_eq!(
+            server.join().unwrap().recv().unwrap(),
+            json!({"jsonrpc": "2.0", "method": "shutdown", "id": 0})
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let dir = std::env::temp_dir().join(format!("lsp-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("connect.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stand_in_server(stream.try_clone().unwrap(), stream)
+        });
+
+        let client = Client::with_transport(Transport::unix(&path).unwrap());
+        client.request::<Shutdown>(None).unwrap();
+        server.join().unwrap().recv().unwrap();
+
+        let path = dir.join("accept.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let stream = UnixStream::connect(path).unwrap();
+                stand_in_server(stream.try_clone().unwrap(), stream)
+            }
+        });
+
+        let client = Client::with_transport(Transport::unix_accept(&listener).unwrap());
+        client.request::<Shutdown>(None).unwrap();
+        server.join().unwrap().recv().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // echo the request id back, without any Content-Length headers
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+
+            let mut requests = vec![];
+            while let Ok(tungstenite::Message::Text(request)) = socket.read() {
+                let request: Value = serde_json::from_str(&request).unwrap();
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "result": null,
+                    "id": request["id"],
+                });
+
+                socket
+                    .send(tungstenite::Message::text(response.to_string()))
+                    .unwrap();
+                requests.push(request.to_string());
+            }
+
+            requests
+        });
+
+        let client =
+            Client::with_transport(Transport::websocket(&format!("ws://{}", addr)).unwrap());
+
+        client.request::<Shutdown>(None).unwrap();
+        client.request::<Shutdown>(None).unwrap();
+        drop(client);
+
+        insta::assert_debug_snapshot!(server.join().unwrap(), @r#"
+        [
+            "{\"id\":0,\"jsonrpc\":\"2.0\",\"method\":\"shutdown\"}",
+            "{\"id\":1,\"jsonrpc\":\"2.0\",\"method\":\"shutdown\"}",
+        ]
+        "#);
+
+        let err = Transport::websocket("wss://localhost").err().unwrap();
+        insta::assert_snapshot!(err, @r#"only ws:// urls are supported, not "wss://localhost""#);
+    }
+}
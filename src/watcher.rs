@@ -0,0 +1,490 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use lsp_types::notification::{DidChangeWatchedFiles, Notification};
+use lsp_types::{
+    DidChangeWatchedFilesParams, DidChangeWatchedFilesRegistrationOptions, FileChangeType,
+    FileEvent, GlobPattern, OneOf, Uri, WatchKind,
+};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+
+use crate::glob::Glob;
+use crate::Client;
+
+// must be started before the client is initialized, so the client advertises
+// that it watches files. stops when dropped
+pub struct Watcher {
+    client: Client,
+    _watcher: RecommendedWatcher,
+}
+
+struct Pattern {
+    glob: Glob,
+    base: Option<PathBuf>,
+    kind: WatchKind,
+}
+
+impl Watcher {
+    pub fn start(client: &Client, root: impl AsRef<Path>, delay: Duration) -> Result<Self> {
+        let root = root
+            .as_ref()
+            .canonicalize()
+            .context("watching workspace root")?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        client.set_watches_files(true);
+
+        std::thread::spawn({
+            let client = client.clone();
+            move || forward(rx, &client, &root, delay)
+        });
+
+        Ok(Self {
+            client: client.clone(),
+            _watcher: watcher,
+        })
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.client.set_watches_files(false);
+    }
+}
+
+impl Pattern {
+    // `None` if the pattern is invalid, there is nothing to watch then
+    fn new(pattern: GlobPattern, kind: Option<WatchKind>, root: &Path) -> Option<Self> {
+        let (pattern, base) = match pattern {
+            GlobPattern::String(pattern) => (pattern, None),
+            GlobPattern::Relative(relative) => {
+                let base = match relative.base_uri {
+                    OneOf::Left(folder) => folder.uri,
+                    OneOf::Right(uri) => uri,
+                };
+                (relative.pattern, Some(file_path(&base)))
+            }
+        };
+
+        let glob = Glob::new(&pattern)?;
+
+        Some(Self {
+            glob,
+            // relative string patterns are relative to the workspace
+            base: base.or_else(|| {
+                Path::new(&pattern)
+                    .is_relative()
+                    .then(|| root.to_path_buf())
+            }),
+            kind: kind.unwrap_or(WatchKind::all()),
+        })
+    }
+
+    fn matches(&self, path: &Path, typ: FileChangeType) -> bool {
+        let kind = match typ {
+            FileChangeType::CREATED => WatchKind::Create,
+            FileChangeType::CHANGED => WatchKind::Change,
+            _ => WatchKind::Delete,
+        };
+
+        let path = match &self.base {
+            Some(base) => match path.strip_prefix(base) {
+                Ok(path) => path,
+                Err(_) => return false,
+            },
+            None => path,
+        };
+
+        let path = path
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "/");
+
+        self.kind.contains(kind) && self.glob.is_match(&path)
+    }
+}
+
+// until the watcher is dropped or the server is gone
+fn forward(
+    rx: mpsc::Receiver<notify::Result<notify::Event>>,
+    client: &Client,
+    root: &Path,
+    delay: Duration,
+) {
+    while let Ok(first) = rx.recv() {
+        let mut batch = Batch::default();
+        batch.add(first);
+
+        let deadline = Instant::now() + delay;
+        while let Ok(event) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            batch.add(event);
+        }
+
+        // whatever the server registered by now, the client records it
+        let patterns = patterns(client, root);
+        let changes: Vec<_> = batch
+            .changes
+            .into_iter()
+            .filter(|(path, typ)| patterns.iter().any(|pattern| pattern.matches(path, *typ)))
+            .filter_map(|(path, typ)| Some(FileEvent::new(file_uri(&path)?, typ)))
+            .collect();
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        let params = DidChangeWatchedFilesParams { changes };
+        if client
+            .notify::<DidChangeWatchedFiles>(Some(params))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// registrations with invalid options watch nothing
+fn patterns(client: &Client, root: &Path) -> Vec<Pattern> {
+    let capabilities = client.capabilities();
+
+    capabilities
+        .registered(DidChangeWatchedFiles::METHOD)
+        .filter_map(|options| {
+            serde_json::from_value::<DidChangeWatchedFilesRegistrationOptions>(options.clone()).ok()
+        })
+        .flat_map(|options| options.watchers)
+        .filter_map(|watcher| Pattern::new(watcher.glob_pattern, watcher.kind, root))
+        .collect()
+}
+
+// the changes to each path merged into one
+#[derive(Default)]
+struct Batch {
+    changes: Vec<(PathBuf, FileChangeType)>,
+}
+
+impl Batch {
+    fn add(&mut self, event: notify::Result<notify::Event>) {
+        // a failing watch can't be told apart from a quiet one
+        let Ok(event) = event else {
+            return;
+        };
+
+        let typ = |kind: &EventKind| match kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                Some(FileChangeType::CREATED)
+            }
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                Some(FileChangeType::DELETED)
+            }
+            // the contents are the same
+            EventKind::Modify(ModifyKind::Metadata(_)) => None,
+            EventKind::Modify(_) => Some(FileChangeType::CHANGED),
+            _ => None,
+        };
+
+        match (event.kind, event.paths.as_slice()) {
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                self.push(from.clone(), FileChangeType::DELETED);
+                self.push(to.clone(), FileChangeType::CREATED);
+            }
+            // either end of a rename, which one is only known from the disk
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Any | RenameMode::Other)), paths) => {
+                for path in paths {
+                    let typ = if path.exists() {
+                        FileChangeType::CREATED
+                    } else {
+                        FileChangeType::DELETED
+                    };
+                    self.push(path.clone(), typ);
+                }
+            }
+            (kind, paths) => {
+                if let Some(typ) = typ(&kind) {
+                    paths.iter().for_each(|path| self.push(path.clone(), typ));
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, path: PathBuf, typ: FileChangeType) {
+        let Some(i) = self.changes.iter().position(|(p, _)| *p == path) else {
+            self.changes.push((path, typ));
+            return;
+        };
+
+        let merged = match (self.changes[i].1, typ) {
+            (FileChangeType::CREATED, FileChangeType::DELETED) => None,
+            (FileChangeType::CREATED, _) => Some(FileChangeType::CREATED),
+            (FileChangeType::DELETED, FileChangeType::CREATED) => Some(FileChangeType::CHANGED),
+            (_, typ) => Some(typ),
+        };
+
+        match merged {
+            Some(typ) => self.changes[i].1 = typ,
+            // the server never knew about it
+            None => drop(self.changes.remove(i)),
+        }
+    }
+}
+
+fn file_uri(path: &Path) -> Option<Uri> {
+    let path = path.to_str()?;
+
+    let mut uri = String::from("file://");
+    // windows paths start with a drive
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+
+    for b in path.bytes() {
+        match b {
+            b'\\' => uri.push('/'),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(b as char)
+            }
+            b => write!(uri, "%{:02X}", b).unwrap(),
+        }
+    }
+
+    Uri::from_str(&uri).ok()
+}
+
+fn file_path(uri: &Uri) -> PathBuf {
+    let path = uri.path().as_estr().decode().into_string_lossy();
+
+    PathBuf::from(path.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use lsp_types::request::{RegisterCapability, UnregisterCapability};
+    use lsp_types::{Registration, RegistrationParams, Unregistration, UnregistrationParams};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::MockServer;
+
+    fn register(watchers: Value) -> RegistrationParams {
+        RegistrationParams {
+            registrations: vec![Registration {
+                id: "watch".to_string(),
+                method: DidChangeWatchedFiles::METHOD.to_string(),
+                register_options: Some(json!({ "watchers": watchers })),
+            }],
+        }
+    }
+
+    // returns every change the server got so far, by file name
+    fn wait_for(handle: &crate::MockHandle, name: &str) -> Vec<(String, i64)> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        loop {
+            let changes: Vec<_> = handle
+                .received()
+                .into_iter()
+                .filter(|msg| msg["method"] == DidChangeWatchedFiles::METHOD)
+                .flat_map(|msg| msg["params"]["changes"].as_array().unwrap().clone())
+                .map(|change| {
+                    let uri = change["uri"].as_str().unwrap();
+                    let name = uri.rsplit('/').next().unwrap().to_string();
+                    (name, change["type"].as_i64().unwrap())
+                })
+                .collect();
+
+            if changes.iter().any(|(n, _)| n == name) {
+                return changes;
+            }
+
+            assert!(Instant::now() < deadline, "no change of {}", name);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+
+        let (transport, handle) = MockServer::new().start();
+        let client = Client::with_transport(transport);
+        // still answers registrations with the watcher running
+        let registered = Arc::new(AtomicUsize::new(0));
+        client.on_request::<RegisterCapability>({
+            let registered = registered.clone();
+            move |_| {
+                registered.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+        let watcher = Watcher::start(&client, dir.path(), Duration::from_millis(50)).unwrap();
+
+        let root = file_uri(&dir.path().canonicalize().unwrap()).unwrap();
+        client.initialize(root).unwrap();
+
+        let watchers = json!([
+            {"globPattern": "**/*.rs"},
+            {"globPattern": "**/Cargo.toml", "kind": 4},
+        ]);
+        handle
+            .request::<RegisterCapability>(register(watchers))
+            .unwrap();
+        assert_eq!(registered.load(Ordering::SeqCst), 1);
+
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        // only deletes are watched
+        std::fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        let changes = wait_for(&handle, "lib.rs");
+        assert_eq!(changes[0], ("lib.rs".to_string(), 1));
+        assert!(changes.iter().all(|(name, _)| name == "lib.rs"));
+
+        std::fs::remove_file(dir.path().join("Cargo.toml")).unwrap();
+        std::fs::remove_file(dir.path().join("src/lib.rs")).unwrap();
+        let changes = wait_for(&handle, "Cargo.toml");
+        assert!(changes.contains(&("lib.rs".to_string(), 3)));
+        assert!(!changes.iter().any(|(name, _)| name == "notes.txt"));
+
+        handle
+            .request::<UnregisterCapability>(UnregistrationParams {
+                unregisterations: vec![Unregistration {
+                    id: "watch".to_string(),
+                    method: DidChangeWatchedFiles::METHOD.to_string(),
+                }],
+            })
+            .unwrap();
+
+        std::fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!wait_for(&handle, "Cargo.toml")
+            .iter()
+            .any(|(name, _)| name == "main.rs"));
+
+        let capabilities = &handle.received()[0]["params"]["capabilities"];
+        assert_eq!(
+            capabilities["workspace"]["didChangeWatchedFiles"],
+            json!({"dynamicRegistration": true, "relativePatternSupport": true})
+        );
+
+        drop(watcher);
+        assert!(!client.watches_files());
+    }
+
+    #[test]
+    fn test_relative_pattern() {
+        let root = Path::new("/work");
+        let base = Uri::from_str("file:///work/my%20crate").unwrap();
+        let pattern = GlobPattern::Relative(lsp_types::RelativePattern {
+            base_uri: OneOf::Right(base),
+            pattern: "src/**/*.rs".to_string(),
+        });
+        let pattern = Pattern::new(pattern, Some(WatchKind::Create), root).unwrap();
+
+        assert!(pattern.matches(
+            Path::new("/work/my crate/src/a/b.rs"),
+            FileChangeType::CREATED
+        ));
+        assert!(!pattern.matches(
+            Path::new("/work/my crate/src/a/b.rs"),
+            FileChangeType::DELETED
+        ));
+        assert!(!pattern.matches(Path::new("/work/src/b.rs"), FileChangeType::CREATED));
+
+        // relative to the workspace
+        let pattern = Pattern::new(GlobPattern::String("*.toml".to_string()), None, root).unwrap();
+        assert!(pattern.matches(Path::new("/work/Cargo.toml"), FileChangeType::CHANGED));
+        assert!(!pattern.matches(Path::new("/work/sub/Cargo.toml"), FileChangeType::CHANGED));
+
+        assert_eq!(
+            file_uri(Path::new("/work/my crate/é.rs")).unwrap().as_str(),
+            "file:///work/my%20crate/%C3%A9.rs"
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("f"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let event = |kind, path: &str| {
+            let path = PathBuf::from(path.replace("{root}", root));
+            Ok(notify::Event::new(kind).add_path(path))
+        };
+
+        let mut batch = Batch::default();
+        batch.add(event(
+            EventKind::Create(notify::event::CreateKind::File),
+            "/a",
+        ));
+        batch.add(event(EventKind::Modify(ModifyKind::Any), "/a"));
+        batch.add(event(
+            EventKind::Create(notify::event::CreateKind::File),
+            "/b",
+        ));
+        batch.add(event(
+            EventKind::Remove(notify::event::RemoveKind::File),
+            "/b",
+        ));
+        batch.add(event(
+            EventKind::Remove(notify::event::RemoveKind::File),
+            "/c",
+        ));
+        batch.add(event(
+            EventKind::Create(notify::event::CreateKind::File),
+            "/c",
+        ));
+        batch.add(event(
+            EventKind::Access(notify::event::AccessKind::Any),
+            "/d",
+        ));
+        batch.add(event(
+            EventKind::Modify(ModifyKind::Metadata(notify::event::MetadataKind::Any)),
+            "/e",
+        ));
+        // a rename seen from either end
+        batch.add(event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::Any)),
+            "{root}/f",
+        ));
+        batch.add(event(
+            EventKind::Modify(ModifyKind::Name(RenameMode::Any)),
+            "{root}/g",
+        ));
+
+        let changes: Vec<_> = batch
+            .changes
+            .into_iter()
+            .map(|(path, typ)| (path.to_str().unwrap().replace(root, "{root}"), typ))
+            .collect();
+        insta::assert_debug_snapshot!(changes, @r#"
+        [
+            (
+                "/a",
+                Created,
+            ),
+            (
+                "/c",
+                Changed,
+            ),
+            (
+                "{root}/f",
+                Created,
+            ),
+            (
+                "{root}/g",
+                Deleted,
+            ),
+        ]
+        "#);
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use lsp_client::{Client, PositionEncoding, Replay};
+use lsp_types::Uri;
+use serde_json::Value;
+
+// a hand-written session, in the shape of rust-analyzer's answers
+#[test]
+fn test_replay_facade() {
+    let replay = Replay::file("tests/replay/facade.jsonl").expect("failed to load recording");
+    let client = Client::with_transport(replay.transport());
+
+    let capabilities = client
+        .initialize(Uri::from_str("file:///").unwrap())
+        .expect("failed to initialize");
+    assert!(capabilities.document_symbol_provider.is_some());
+    assert_eq!(client.position_encoding(), PositionEncoding::Utf8);
+
+    let uri = Uri::from_str("file:///src/main.rs").unwrap();
+    client
+        .open(&uri, "fn main() { if true { let a = 1; }}")
+        .expect("failed to open file");
+
+    let symbols = client.symbols(&uri).expect("failed to get symbols");
+    let names: Vec<_> = symbols.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["main", "a"]);
+
+    let main = &symbols[0];
+    let definitions = client
+        .definitions(&uri, main)
+        .expect("failed to get definitions");
+    assert_eq!(definitions, vec![uri.clone()]);
+
+    let references = client
+        .references(&uri, main)
+        .expect("failed to get references");
+    assert!(references.is_empty());
+
+    client.shutdown().expect("failed to shut down");
+
+    replay.finish().expect("client diverged from the recording");
+}
+
+// a hand-written session in the format `code-graph --record` writes, for the
+// project in tests/replay/project. `{root}` and `{version}` are filled in here
+#[test]
+fn test_replay_code_graph() {
+    let project = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/replay/project");
+    let root = format!("file://{}", project.display());
+
+    let recording = std::fs::read_to_string("tests/replay/code_graph.jsonl")
+        .expect("failed to load recording")
+        .replace("{root}", &root)
+        .replace("{version}", env!("CARGO_PKG_VERSION"));
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("code_graph.jsonl");
+    std::fs::write(&path, recording).unwrap();
+
+    let mut code_graph = Command::new(env!("CARGO_BIN_EXE_code-graph"))
+        .args(["--replay", path.to_str().unwrap(), "--settle", "0", &root])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run code-graph");
+
+    let mut stdin = code_graph.stdin.take().unwrap();
+    stdin.write_all(b"src/util.rs\nsrc/main.rs\n").unwrap();
+    drop(stdin);
+
+    let output = code_graph.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "code-graph failed:\n{}", stderr);
+
+    let mut graph: Value = serde_json::from_slice(&output.stdout).unwrap();
+    for key in ["nodes", "edges"] {
+        graph[key]
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|value| value.to_string());
+    }
+
+    insta::assert_json_snapshot!(graph, @r#"
+    {
+      "edges": [
+        [
+          "/src/main.rs",
+          "/src/util.rs"
+        ]
+      ],
+      "nodes": [
+        "/src/main.rs",
+        "/src/util.rs"
+      ]
+    }
+    "#);
+}
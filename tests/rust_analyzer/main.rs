@@ -22,7 +22,7 @@ fn test_rust_analyzer() {
     let input = BufReader::new(child.stdout.take().expect("Failed to take stdout"));
     let output = child.stdin.take().expect("Failed to take stdin");
 
-    let mut client = Client::new(Box::new(input), Box::new(output));
+    let client = Client::new(Box::new(input), Box::new(output));
 
     let init_resp = client.initialize(Uri::from_str("file:///").unwrap());
 
@@ -112,4 +112,7 @@ fn test_rust_analyzer() {
       }
     ]
     "#);
+
+    child.kill().expect("failed to kill rust analyzer");
+    child.wait().expect("failed to wait for rust analyzer");
 }